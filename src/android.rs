@@ -0,0 +1,129 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passing sockets and files to a child process using the same environment variables as Android
+//! init, so that it can find them with `android_get_control_socket` and
+//! `android_get_control_file`, or [`take_android_socket`](crate::inherited::take_android_socket)
+//! and [`take_android_file`](crate::inherited::take_android_file).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::android::AndroidFds;
+//! use std::fs::File;
+//! use std::os::unix::net::UnixListener;
+//! use std::process::Command;
+//!
+//! let socket = UnixListener::bind("/dev/socket/myservice").unwrap();
+//! let log = File::create("/data/myservice.log").unwrap();
+//!
+//! let mut command = Command::new("myservice");
+//! let mut fds = AndroidFds::new(3);
+//! fds.socket("myservice", socket.into())
+//!     .file("/data/myservice.log", log.into());
+//! fds.apply(&mut command).unwrap();
+//! command.spawn().unwrap();
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, FdMappingCollision,
+    inherited::{android_file_env_var, android_socket_env_var},
+};
+use std::{
+    os::fd::{OwnedFd, RawFd},
+    path::Path,
+    process::Command,
+};
+
+/// A set of sockets and files to pass to a child process, advertised in `ANDROID_SOCKET_<name>`
+/// and `ANDROID_FILE_<path>` environment variables.
+#[derive(Debug)]
+pub struct AndroidFds {
+    mappings: Vec<FdMapping>,
+    env: Vec<(String, String)>,
+    next_fd: RawFd,
+}
+
+impl AndroidFds {
+    /// Creates an empty set, which will map file descriptors to consecutive numbers in the child
+    /// starting from `first_fd`.
+    pub fn new(first_fd: RawFd) -> Self {
+        Self {
+            mappings: Vec::new(),
+            env: Vec::new(),
+            next_fd: first_fd,
+        }
+    }
+
+    /// Adds a socket with the given name, advertised in `ANDROID_SOCKET_<name>`.
+    pub fn socket(&mut self, name: &str, fd: OwnedFd) -> &mut Self {
+        self.add(android_socket_env_var(name), fd)
+    }
+
+    /// Adds a file opened from the given path, advertised in `ANDROID_FILE_<path>` with every
+    /// character of the path other than ASCII letters and digits replaced by `_`.
+    ///
+    /// The child checks that the file descriptor refers to the file at this path, so it should be
+    /// the same path that the file was opened from.
+    pub fn file(&mut self, path: impl AsRef<Path>, fd: OwnedFd) -> &mut Self {
+        self.add(android_file_env_var(path), fd)
+    }
+
+    fn add(&mut self, env_var: String, parent_fd: OwnedFd) -> &mut Self {
+        self.env.push((env_var, self.next_fd.to_string()));
+        self.mappings.push(FdMapping {
+            parent_fd,
+            child_fd: self.next_fd,
+        });
+        self.next_fd += 1;
+        self
+    }
+
+    /// Adds the file descriptors and environment variables to the given command.
+    ///
+    /// As with [`CommandFdExt::fd_mappings`], this shouldn't be combined with other mappings on the
+    /// same command.
+    pub fn apply(self, command: &mut Command) -> Result<(), FdMappingCollision> {
+        command.envs(self.env).fd_mappings(self.mappings)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{fs::File, net::TcpListener};
+    use tempfile::tempdir;
+
+    #[test]
+    fn pass_to_child() {
+        setup();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let file = File::create(&path).unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            r#"[ "$ANDROID_SOCKET_app" = 5 ] && [ -S /proc/self/fd/5 ] && [ "${}" = 6 ] && [ "$(readlink /proc/self/fd/6)" = "{}" ]"#,
+            android_file_env_var(&path),
+            path.display(),
+        ));
+        let mut fds = AndroidFds::new(5);
+        fds.socket("app", socket.into()).file(&path, file.into());
+        fds.apply(&mut command).unwrap();
+        assert!(command.status().unwrap().success());
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs a command with arbitrary file descriptors mapped into it.
+//!
+//! ```text
+//! fdrun [--only-mapped] [--fd N=SOURCE]... [--] COMMAND [ARG]...
+//! ```
+//!
+//! Each `--fd` option maps a file descriptor number in the command to one of the following
+//! sources:
+//!
+//! - `file:PATH[:MODE]` opens a file, where `MODE` is one of `ro` (the default), `wo`, `rw` or
+//!   `append`. `wo` creates and truncates the file, `rw` and `append` create it if necessary.
+//! - `tcp-listen:ADDRESS`, `udp:ADDRESS` and `unix-listen:PATH` create a listening socket.
+//! - `listen:SPEC` creates a socket or FIFO from a spec as described in [`command_fds::listen`].
+//! - `tcp-connect:ADDRESS` and `unix-connect:PATH` connect a socket.
+//! - `inherit:N` passes on file descriptor `N` of `fdrun` itself.
+//!
+//! With `--only-mapped`, file descriptors other than stdin, stdout, stderr and the mapped ones are
+//! not passed on to the command.
+//!
+//! The command is executed in place of `fdrun`, so it keeps the same PID.
+
+use command_fds::{
+    CommandFdExt, FdMapping,
+    listen::{ListenAddress, ListenerSpec},
+};
+use nix::{
+    errno::Errno,
+    fcntl::{FcntlArg, FdFlag, fcntl},
+    libc,
+};
+use std::{
+    env::args_os,
+    ffi::OsString,
+    fs::{OpenOptions, read_dir},
+    net::TcpStream,
+    os::{
+        fd::{BorrowedFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process::{Command, exit},
+};
+
+const USAGE: &str = "Usage: fdrun [--only-mapped] [--fd N=SOURCE]... [--] COMMAND [ARG]...";
+
+/// A source for a file descriptor to map into the command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Source {
+    File { path: String, mode: FileMode },
+    Listen(ListenerSpec),
+    TcpConnect(String),
+    UnixConnect(String),
+    Inherit(RawFd),
+}
+
+/// How to open a file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FileMode {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    Append,
+}
+
+/// The parsed command line.
+#[derive(Debug, Eq, PartialEq)]
+struct Args {
+    only_mapped: bool,
+    fds: Vec<(RawFd, Source)>,
+    command: Vec<OsString>,
+}
+
+fn main() {
+    let args = match parse_args(args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("fdrun: {e}\n{USAGE}");
+            exit(2);
+        }
+    };
+    let error = match run(args) {
+        Ok(error) => format!("failed to execute command: {error}"),
+        Err(error) => error,
+    };
+    eprintln!("fdrun: {error}");
+    exit(127);
+}
+
+/// Sets up and executes the command. This only returns if something goes wrong, either with an
+/// error setting up the mappings or the error from `exec`.
+fn run(args: Args) -> Result<std::io::Error, String> {
+    let mappings = args
+        .fds
+        .iter()
+        .map(|(child_fd, source)| {
+            Ok(FdMapping {
+                parent_fd: source
+                    .open()
+                    .map_err(|e| format!("failed to open FD {child_fd}: {e}"))?,
+                child_fd: *child_fd,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if args.only_mapped {
+        set_cloexec_on_unmapped().map_err(|e| format!("failed to set FD_CLOEXEC: {e}"))?;
+    }
+
+    let mut command = Command::new(&args.command[0]);
+    command.args(&args.command[1..]);
+    command.fd_mappings(mappings).map_err(|e| e.to_string())?;
+    Ok(command.exec())
+}
+
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Args, String> {
+    let mut args = args.into_iter();
+    let mut only_mapped = false;
+    let mut fds = Vec::new();
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--only-mapped") => only_mapped = true,
+            Some("--fd") => {
+                let value = args.next().ok_or("--fd requires a value")?;
+                fds.push(parse_fd(
+                    value
+                        .to_str()
+                        .ok_or_else(|| format!("invalid --fd value {value:?}"))?,
+                )?);
+            }
+            Some(arg) if arg.starts_with("--fd=") => fds.push(parse_fd(&arg["--fd=".len()..])?),
+            Some("--") => {
+                command.extend(args.by_ref());
+            }
+            Some(arg) if arg.starts_with('-') && command.is_empty() => {
+                return Err(format!("unknown option {arg:?}"));
+            }
+            _ => {
+                command.push(arg);
+                command.extend(args.by_ref());
+            }
+        }
+    }
+    if command.is_empty() {
+        return Err("no command given".to_owned());
+    }
+    Ok(Args {
+        only_mapped,
+        fds,
+        command,
+    })
+}
+
+/// Parses an `N=SOURCE` mapping.
+fn parse_fd(value: &str) -> Result<(RawFd, Source), String> {
+    let (fd, source) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid --fd value {value:?}, expected N=SOURCE"))?;
+    let fd = fd
+        .parse()
+        .ok()
+        .filter(|fd| *fd >= 0)
+        .ok_or_else(|| format!("invalid FD number {fd:?}"))?;
+    let (kind, argument) = source
+        .split_once(':')
+        .ok_or_else(|| format!("invalid source {source:?}, expected KIND:ARGUMENT"))?;
+    let listen = |address| Ok(Source::Listen(ListenerSpec::new(address)));
+    let invalid_address = |_| format!("invalid address {argument:?}");
+    let source = match kind {
+        "file" => {
+            let (path, mode) = match argument.rsplit_once(':') {
+                Some((path, "ro")) => (path, FileMode::ReadOnly),
+                Some((path, "wo")) => (path, FileMode::WriteOnly),
+                Some((path, "rw")) => (path, FileMode::ReadWrite),
+                Some((path, "append")) => (path, FileMode::Append),
+                _ => (argument, FileMode::ReadOnly),
+            };
+            Ok(Source::File {
+                path: path.to_owned(),
+                mode,
+            })
+        }
+        "tcp-listen" => listen(ListenAddress::Tcp(
+            argument.parse().map_err(invalid_address)?,
+        )),
+        "udp" => listen(ListenAddress::Udp(
+            argument.parse().map_err(invalid_address)?,
+        )),
+        "unix-listen" => listen(ListenAddress::Unix(argument.into())),
+        "listen" => Ok(Source::Listen(
+            argument.parse().map_err(|e| format!("{e}"))?,
+        )),
+        "tcp-connect" => Ok(Source::TcpConnect(argument.to_owned())),
+        "unix-connect" => Ok(Source::UnixConnect(argument.to_owned())),
+        "inherit" => Ok(Source::Inherit(
+            argument
+                .parse()
+                .ok()
+                .filter(|fd| *fd >= 0)
+                .ok_or_else(|| format!("invalid FD number {argument:?}"))?,
+        )),
+        _ => Err(format!("unknown source kind {kind:?}")),
+    }?;
+    Ok((fd, source))
+}
+
+impl Source {
+    /// Opens the source, returning a new file descriptor with `FD_CLOEXEC` set.
+    fn open(&self) -> Result<OwnedFd, String> {
+        match self {
+            Source::File { path, mode } => {
+                let mut options = OpenOptions::new();
+                match mode {
+                    FileMode::ReadOnly => options.read(true),
+                    FileMode::WriteOnly => options.write(true).create(true).truncate(true),
+                    FileMode::ReadWrite => options.read(true).write(true).create(true),
+                    FileMode::Append => options.append(true).create(true),
+                };
+                Ok(options
+                    .open(path)
+                    .map_err(|e| format!("{path}: {e}"))?
+                    .into())
+            }
+            Source::Listen(spec) => spec.open().map_err(|e| e.to_string()),
+            Source::TcpConnect(address) => Ok(TcpStream::connect(address)
+                .map_err(|e| format!("{address}: {e}"))?
+                .into()),
+            Source::UnixConnect(path) => Ok(UnixStream::connect(path)
+                .map_err(|e| format!("{path}: {e}"))?
+                .into()),
+            Source::Inherit(fd) => {
+                // Safety: `F_GETFD` accepts any integer, and fails with `EBADF` if it isn't an open
+                // file descriptor.
+                if unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                    return Err(format!("FD {fd} is not open: {}", Errno::last()));
+                }
+                // Safety: The FD was checked to be open above, and nothing else in this process
+                // closes it, as no other threads are running. It is only borrowed long enough to
+                // duplicate it.
+                unsafe { BorrowedFd::borrow_raw(*fd) }
+                    .try_clone_to_owned()
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// Sets `FD_CLOEXEC` on all open file descriptors other than stdin, stdout and stderr, so that
+/// only those and the mapped file descriptors are passed on to the command.
+fn set_cloexec_on_unmapped() -> Result<(), String> {
+    let fds = read_dir("/proc/self/fd")
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .filter(|fd| *fd > 2)
+        .collect::<Vec<_>>();
+    for fd in fds {
+        // Safety: The FD is only used for the duration of the call, and if it has been closed
+        // since listing (e.g. it was the directory FD) then `fcntl` will fail with `EBADF`.
+        let _ = fcntl(
+            unsafe { BorrowedFd::borrow_raw(fd) },
+            FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parse_mappings() {
+        let args = parse(&[
+            "--only-mapped",
+            "--fd",
+            "3=file:/tmp/a:b:rw",
+            "--fd=4=tcp-listen:0.0.0.0:80",
+            "--fd",
+            "5=inherit:1",
+            "--fd",
+            "6=listen:http=unix:/run/app.sock,mode=0600",
+            "--",
+            "--not-an-option",
+            "--fd",
+        ])
+        .unwrap();
+        assert!(args.only_mapped);
+        assert_eq!(
+            args.fds,
+            vec![
+                (
+                    3,
+                    Source::File {
+                        path: "/tmp/a:b".to_owned(),
+                        mode: FileMode::ReadWrite
+                    }
+                ),
+                (
+                    4,
+                    Source::Listen(ListenerSpec::new(ListenAddress::Tcp(
+                        "0.0.0.0:80".parse().unwrap()
+                    )))
+                ),
+                (5, Source::Inherit(1)),
+                (
+                    6,
+                    Source::Listen("http=unix:/run/app.sock,mode=0600".parse().unwrap())
+                ),
+            ]
+        );
+        assert_eq!(args.command, vec!["--not-an-option", "--fd"]);
+
+        let args = parse(&["--fd", "3=file:/tmp/x", "cat", "--fd"]).unwrap();
+        assert_eq!(
+            args.fds,
+            vec![(
+                3,
+                Source::File {
+                    path: "/tmp/x".to_owned(),
+                    mode: FileMode::ReadOnly
+                }
+            )]
+        );
+        assert_eq!(args.command, vec!["cat", "--fd"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for args in [
+            &[][..],
+            &["--fd"],
+            &["--fd", "3=file:/tmp/x"],
+            &["--bogus", "cat"],
+            &["--fd", "x=file:/tmp/x", "cat"],
+            &["--fd", "-1=file:/tmp/x", "cat"],
+            &["--fd", "3=socket:x", "cat"],
+            &["--fd", "3=tcp-listen:localhost", "cat"],
+            &["--fd", "3=inherit:stdout", "cat"],
+            &["--fd", "3=inherit:-1", "cat"],
+        ] {
+            assert!(parse(args).is_err(), "{args:?} should fail");
+        }
+    }
+}
@@ -0,0 +1,386 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs a socket-activated service without systemd, in the same way as `systemd-socket-activate`.
+//!
+//! ```text
+//! socket-activate [OPTION]... [--] COMMAND [ARG]...
+//! ```
+//!
+//! Options:
+//!
+//! - `-l`, `--listen ADDRESS`: Listens on the given address. This may be a port number, an
+//!   `IP:PORT` address, a Unix socket path starting with `/`, an abstract Unix socket name starting
+//!   with `@`, or a spec as described in [`command_fds::listen`] such as `http=tcp:[::]:80`. May be
+//!   given more than once.
+//! - `-d`, `--datagram`: Uses UDP rather than TCP for port numbers and `IP:PORT` addresses.
+//! - `-a`, `--accept`: Accepts connections, and spawns a new instance of the command for each one.
+//! - `--now`: Starts the command immediately, rather than waiting for the first connection.
+//! - `--fdname NAME[:NAME]...`: Names the listeners in `LISTEN_FDNAMES`, in order.
+//! - `-E`, `--setenv VAR[=VALUE]`: Passes the given environment variable to the command.
+//!
+//! The listeners are passed to the command starting from file descriptor 3, with `LISTEN_FDS`,
+//! `LISTEN_PID` and `LISTEN_FDNAMES` set according to the systemd socket activation protocol.
+//! Without `--accept` the command is executed in place of `socket-activate`, so it keeps the same
+//! PID.
+
+use command_fds::{
+    FdMapping, NamedFdMapping,
+    listen::{ListenAddress, ListenerSpec},
+    protocol::{FdProtocol, SD_LISTEN_FDS_START, Systemd},
+};
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+    sys::{
+        socket::{SockFlag, accept4},
+        wait::{WaitPidFlag, WaitStatus, waitpid},
+    },
+    unistd::{ForkResult, fork, getpid},
+};
+use std::{
+    env::{args_os, var_os},
+    ffi::OsString,
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, exit},
+};
+
+const USAGE: &str = "Usage: socket-activate [-l ADDRESS]... [-d] [-a] [--now] [--fdname NAMES] \
+    [-E VAR[=VALUE]]... [--] COMMAND [ARG]...";
+
+/// The parsed command line.
+#[derive(Debug, Eq, PartialEq)]
+struct Args {
+    listeners: Vec<ListenerSpec>,
+    accept: bool,
+    now: bool,
+    env: Vec<(OsString, OsString)>,
+    command: Vec<OsString>,
+}
+
+fn main() {
+    let args = match parse_args(args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("socket-activate: {e}\n{USAGE}");
+            exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("socket-activate: {e}");
+        exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let listeners = args
+        .listeners
+        .iter()
+        .map(|spec| {
+            let fd = spec.open().map_err(|e| e.to_string())?;
+            eprintln!("socket-activate: listening on {spec}");
+            Ok(fd)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if args.accept {
+        loop {
+            let ready = wait_for_readable(&listeners)?;
+            reap_children();
+            for listener in ready.into_iter().map(|index| &listeners[index]) {
+                let connection = match accept4(listener.as_raw_fd(), SockFlag::SOCK_CLOEXEC) {
+                    // Safety: `accept4` just returned this FD, so we own it.
+                    Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+                    Err(Errno::EAGAIN | Errno::EINTR | Errno::ECONNABORTED) => continue,
+                    Err(e) => return Err(format!("accept failed: {e}")),
+                };
+                spawn_for_connection(&args, connection)?;
+            }
+        }
+    } else {
+        if !args.now {
+            wait_for_readable(&listeners)?;
+        }
+        let names = args
+            .listeners
+            .iter()
+            .map(|spec| spec.name.clone())
+            .collect();
+        let error = exec_service(&args, listeners, names);
+        Err(format!("failed to execute command: {error}"))
+    }
+}
+
+/// Forks a child process to run the command with the given connection.
+fn spawn_for_connection(args: &Args, connection: OwnedFd) -> Result<(), String> {
+    // Safety: This program is single-threaded, so it is safe to run arbitrary code in the child.
+    match unsafe { fork() }.map_err(|e| format!("fork failed: {e}"))? {
+        ForkResult::Parent { child } => {
+            eprintln!("socket-activate: spawned {child} for connection");
+            Ok(())
+        }
+        ForkResult::Child => {
+            let error = exec_service(args, vec![connection], vec![Some("connection".to_owned())]);
+            eprintln!("socket-activate: failed to execute command: {error}");
+            exit(127);
+        }
+    }
+}
+
+/// Executes the command in place of the current process, passing it the given file descriptors
+/// according to the systemd socket activation protocol.
+fn exec_service(args: &Args, fds: Vec<OwnedFd>, names: Vec<Option<String>>) -> io::Error {
+    let mut command = Command::new(&args.command[0]);
+    command
+        .args(&args.command[1..])
+        .envs(args.env.iter().map(|(key, value)| (key, value)));
+    let fds = fds
+        .into_iter()
+        .zip(names)
+        .zip(SD_LISTEN_FDS_START..)
+        .map(|((parent_fd, name), child_fd)| NamedFdMapping {
+            name,
+            mapping: FdMapping {
+                parent_fd,
+                child_fd,
+            },
+        })
+        .collect();
+    let protocol = Systemd {
+        listen_pid: Some(getpid().as_raw() as u32),
+    };
+    let advertisement = match protocol.advertise(fds) {
+        Ok(advertisement) => advertisement,
+        Err(e) => return io::Error::other(e),
+    };
+    if let Err(e) = advertisement.apply(&mut command) {
+        return io::Error::other(e);
+    }
+    command.exec()
+}
+
+/// Waits until at least one of the given file descriptors is readable, and returns the indices of
+/// those which are.
+fn wait_for_readable(fds: &[OwnedFd]) -> Result<Vec<usize>, String> {
+    let mut poll_fds = fds
+        .iter()
+        .map(|fd| PollFd::new(fd.as_fd(), PollFlags::POLLIN))
+        .collect::<Vec<_>>();
+    loop {
+        match poll(&mut poll_fds, PollTimeout::NONE) {
+            Ok(_) => {
+                return Ok(poll_fds
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, poll_fd)| poll_fd.any().unwrap_or(false))
+                    .map(|(index, _)| index)
+                    .collect());
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(format!("poll failed: {e}")),
+        }
+    }
+}
+
+/// Reaps any child processes which have exited, without blocking.
+fn reap_children() {
+    while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+        match status {
+            WaitStatus::StillAlive => break,
+            WaitStatus::Exited(pid, code) => {
+                eprintln!("socket-activate: child {pid} exited with status {code}");
+            }
+            WaitStatus::Signaled(pid, signal, _) => {
+                eprintln!("socket-activate: child {pid} killed by {signal}");
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Args, String> {
+    let mut args = args.into_iter();
+    let mut addresses = Vec::new();
+    let mut datagram = false;
+    let mut accept = false;
+    let mut now = false;
+    let mut names = Vec::new();
+    let mut env = Vec::new();
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.to_str().filter(|arg| arg.starts_with('-')) else {
+            command.push(arg);
+            break;
+        };
+        let (option, inline_value) = match option.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value.to_owned())),
+            _ => (option, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next()?.into_string().ok())
+                .ok_or_else(|| format!("{option} requires a value"))
+        };
+        match option {
+            "-l" | "--listen" => addresses.push(value()?),
+            "-d" | "--datagram" => datagram = true,
+            "-a" | "--accept" => accept = true,
+            "--now" => now = true,
+            "--fdname" => names.extend(value()?.split(':').map(str::to_owned)),
+            "-E" | "--setenv" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((key, value)) => env.push((key.into(), value.into())),
+                    None => {
+                        if let Some(current) = var_os(&value) {
+                            env.push((value.into(), current));
+                        }
+                    }
+                }
+            }
+            "--" => break,
+            _ => return Err(format!("unknown option {option:?}")),
+        }
+    }
+    command.extend(args);
+
+    if command.is_empty() {
+        return Err("no command given".to_owned());
+    }
+    if addresses.is_empty() {
+        return Err("no listeners given".to_owned());
+    }
+    if names.len() > addresses.len() {
+        return Err("more names than listeners given".to_owned());
+    }
+    let mut listeners = addresses
+        .iter()
+        .map(|address| parse_address(address, datagram))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (listener, name) in listeners.iter_mut().zip(names) {
+        listener.name = Some(name);
+    }
+    if accept
+        && listeners
+            .iter()
+            .any(|listener| matches!(listener.address, ListenAddress::Udp(_)))
+    {
+        return Err("--accept can't be used with datagram sockets".to_owned());
+    }
+    Ok(Args {
+        listeners,
+        accept,
+        now,
+        env,
+        command,
+    })
+}
+
+/// Parses a listen address in the formats accepted by `systemd-socket-activate`, or a listener
+/// spec.
+fn parse_address(address: &str, datagram: bool) -> Result<ListenerSpec, String> {
+    let inet = |address| {
+        Ok(ListenerSpec::new(if datagram {
+            ListenAddress::Udp(address)
+        } else {
+            ListenAddress::Tcp(address)
+        }))
+    };
+    if let Ok(port) = address.parse::<u16>() {
+        inet(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+    } else if let Ok(address) = address.parse::<SocketAddr>() {
+        inet(address)
+    } else if address.starts_with('/') {
+        Ok(ListenerSpec::new(ListenAddress::Unix(address.into())))
+    } else if let Some(name) = address.strip_prefix('@') {
+        Ok(ListenerSpec::new(ListenAddress::UnixAbstract(
+            name.to_owned(),
+        )))
+    } else {
+        address.parse().map_err(|e| format!("{e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!(
+            parse_address("8080", false).unwrap().address,
+            ListenAddress::Tcp("[::]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("127.0.0.1:53", true).unwrap().address,
+            ListenAddress::Udp("127.0.0.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("/run/app.sock", false).unwrap().address,
+            ListenAddress::Unix("/run/app.sock".into())
+        );
+        assert_eq!(
+            parse_address("@app", false).unwrap().address,
+            ListenAddress::UnixAbstract("app".to_owned())
+        );
+        let spec = parse_address("http=tcp:127.0.0.1:80,backlog=5", false).unwrap();
+        assert_eq!(spec.name.as_deref(), Some("http"));
+        assert_eq!(spec.backlog, Some(5));
+        assert!(parse_address("localhost:80", false).is_err());
+    }
+
+    #[test]
+    fn parse_options() {
+        let args = parse(&[
+            "-l",
+            "8080",
+            "--listen=/run/app.sock",
+            "--fdname",
+            "http:admin",
+            "-E",
+            "FOO=bar",
+            "--accept",
+            "server",
+            "--now",
+        ])
+        .unwrap();
+        assert!(args.accept);
+        assert!(!args.now);
+        assert_eq!(args.listeners.len(), 2);
+        assert_eq!(args.listeners[1].name.as_deref(), Some("admin"));
+        assert_eq!(args.env, vec![("FOO".into(), "bar".into())]);
+        assert_eq!(args.command, vec!["server", "--now"]);
+
+        for args in [
+            &["server"][..],
+            &["-l", "80"],
+            &["-l"],
+            &["-l", "80", "--bogus", "server"],
+            &["-l", "80", "--fdname", "a:b", "server"],
+            &["-l", "53", "-d", "-a", "server"],
+        ] {
+            assert!(parse(args).is_err(), "{args:?} should fail");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for passing file descriptors to a child process after it has been spawned, over a
+//! control socket.
+//!
+//! # Example
+//!
+//! In the parent process:
+//!
+//! ```no_run
+//! use command_fds::control::spawn_with_control;
+//! use std::net::TcpListener;
+//! use std::os::fd::AsFd;
+//! use std::process::Command;
+//!
+//! let mut child = spawn_with_control(Command::new("worker"), 10).unwrap();
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! for connection in listener.incoming() {
+//!     child.send_fd("connection", connection.unwrap().as_fd()).unwrap();
+//! }
+//! ```
+//!
+//! In the child process:
+//!
+//! ```no_run
+//! use command_fds::inherited::{init_inherited_fds, spawn_late_fd_receiver};
+//!
+//! // SAFETY: This is called before anything else in the program.
+//! unsafe {
+//!     init_inherited_fds().unwrap();
+//! }
+//! spawn_late_fd_receiver().unwrap();
+//!
+//! // FDs sent by the parent can now be taken with `take_named_fd_ownership` once they arrive.
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping,
+    inherited::{CONTROL_FD_ENV_VAR, set_fd_env},
+    transfer::{TransferError, send_fds},
+};
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    os::{
+        fd::{BorrowedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    process::{Child, Command},
+};
+
+/// A child process along with the parent's end of a control socket, over which file descriptors
+/// can be sent to it.
+///
+/// This dereferences to the [`Child`], so it can be waited for or killed as usual.
+#[derive(Debug)]
+pub struct ControlledChild {
+    child: Child,
+    control: UnixStream,
+}
+
+impl ControlledChild {
+    /// Sends the given file descriptor to the child process with the given name.
+    pub fn send_fd(&self, name: &str, fd: BorrowedFd) -> Result<(), TransferError> {
+        self.send_fds(&[(name, fd)])
+    }
+
+    /// Sends the given file descriptors to the child process as a single batch, with the given
+    /// names.
+    pub fn send_fds(&self, fds: &[(&str, BorrowedFd)]) -> Result<(), TransferError> {
+        send_fds(&self.control, fds)
+    }
+
+    /// Returns the parent's end of the control socket.
+    pub fn control_socket(&self) -> &UnixStream {
+        &self.control
+    }
+
+    /// Splits this into the child process and the parent's end of the control socket.
+    pub fn into_parts(self) -> (Child, UnixStream) {
+        (self.child, self.control)
+    }
+}
+
+impl Deref for ControlledChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for ControlledChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+/// Spawns the given command with one end of a new control socket mapped to `control_fd` in the
+/// child process, and advertised in the [`CONTROL_FD_ENV_VAR`] environment variable.
+///
+/// This adds an FD mapping to the command, so the same warning applies as for
+/// [`CommandFdExt::fd_mappings`]: `control_fd` must not be used by any other mapping on the
+/// command. The command is dropped after spawning the child, which closes the parent's copy of the
+/// child's end of the control socket, so that reading from the control socket returns EOF once the
+/// child exits.
+pub fn spawn_with_control(mut command: Command, control_fd: RawFd) -> io::Result<ControlledChild> {
+    let (control, child_control) = UnixStream::pair()?;
+    set_fd_env(&mut command, CONTROL_FD_ENV_VAR, control_fd);
+    command
+        .fd_mappings(vec![FdMapping {
+            parent_fd: child_control.into(),
+            child_fd: control_fd,
+        }])
+        .expect("A single mapping can't collide");
+    let child = command.spawn()?;
+    drop(command);
+    Ok(ControlledChild { child, control })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{fs::File, io::Read, os::fd::AsFd, time::Duration};
+
+    #[test]
+    fn send_to_child() {
+        setup();
+
+        // The child can't receive the FD with a shell script, but can at least check that the
+        // control socket is mapped and read the payload.
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"[ "$COMMAND_FDS_CONTROL_FD" = 9 ] && [ -S /proc/self/fd/9 ] && head -c 4 <&9 >/dev/null"#,
+        );
+        let mut child = spawn_with_control(command, 9).unwrap();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        child.send_fd("file", file.as_fd()).unwrap();
+
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn eof_after_child_exits() {
+        setup();
+
+        let mut child = spawn_with_control(Command::new("true"), 9).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let (_, mut control) = child.into_parts();
+        control
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(control.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
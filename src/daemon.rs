@@ -0,0 +1,312 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Spawning fully detached daemon processes, with file descriptor mappings.
+//!
+//! The daemon is spawned with the traditional double fork: an intermediate child process starts a
+//! new session with `setsid` and forks the daemon, then exits. The daemon is therefore not a
+//! session leader, so can't acquire a controlling terminal, and is reparented to init (or the
+//! nearest subreaper) rather than being a child of the caller.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::FdMapping;
+//! use command_fds::daemon::Daemon;
+//! use std::fs::File;
+//! use std::process::Command;
+//!
+//! let log = File::create("/var/log/legacyd.log").unwrap();
+//! let config = File::open("/etc/legacyd.conf").unwrap();
+//!
+//! let mut daemon = Daemon::new();
+//! daemon
+//!     .stdout(log.try_clone().unwrap())
+//!     .stderr(log)
+//!     .fd_mappings(vec![FdMapping {
+//!         parent_fd: config.into(),
+//!         child_fd: 3,
+//!     }]);
+//! let pid = daemon.spawn(&mut Command::new("legacyd")).unwrap();
+//! ```
+
+use crate::{CommandFdExt, FdMapping, FdMappingCollision, validate_child_fds};
+use nix::{
+    fcntl::OFlag,
+    libc,
+    unistd::{ForkResult, fork, pipe2, read, setsid, write},
+};
+use std::{
+    fs::File,
+    io,
+    os::{fd::OwnedFd, unix::process::CommandExt},
+    process::{Command, Stdio},
+};
+use thiserror::Error;
+
+/// Errors that can occur while spawning a daemon.
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    /// Two or more mappings for the same child FD
+    #[error("Two or more mappings for the same child FD")]
+    Collision(#[from] FdMappingCollision),
+
+    /// Error setting up or spawning the intermediate process, before the daemon was forked
+    #[error("Failed to spawn daemon: {0}")]
+    Io(#[from] io::Error),
+
+    /// The daemon process was forked, but failed to set up its FDs or exec the command
+    #[error("Daemon process {pid} failed to exec: {source}")]
+    Exec {
+        /// The process ID of the daemon, which has already exited.
+        pid: u32,
+        /// The underlying error.
+        source: io::Error,
+    },
+}
+
+/// A builder for spawning a command as a detached daemon.
+///
+/// By default the daemon's stdin, stdout and stderr are all redirected to `/dev/null`.
+#[derive(Debug, Default)]
+pub struct Daemon {
+    stdin: Option<OwnedFd>,
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+    mappings: Vec<FdMapping>,
+}
+
+impl Daemon {
+    /// Creates a new builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the file descriptor to use as the daemon's stdin, rather than `/dev/null`.
+    pub fn stdin(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stdin = Some(fd.into());
+        self
+    }
+
+    /// Sets the file descriptor to use as the daemon's stdout, rather than `/dev/null`.
+    pub fn stdout(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stdout = Some(fd.into());
+        self
+    }
+
+    /// Sets the file descriptor to use as the daemon's stderr, rather than `/dev/null`.
+    pub fn stderr(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stderr = Some(fd.into());
+        self
+    }
+
+    /// Adds the given FD mappings to be applied in the daemon process.
+    pub fn fd_mappings(&mut self, mappings: Vec<FdMapping>) -> &mut Self {
+        self.mappings.extend(mappings);
+        self
+    }
+
+    /// Spawns the given command as a detached daemon, and returns its process ID once it has
+    /// successfully exec'd the command.
+    ///
+    /// This sets the command's stdio, adds a `pre_exec` hook to fork the daemon, and adds the FD
+    /// mappings, so it should only be called once for each command. Any `pre_exec` hooks already
+    /// added to the command are run in the intermediate process, and their effects are inherited
+    /// by the daemon. Note that the daemon's working directory isn't changed, so you may want to
+    /// set [`Command::current_dir`] to `/`.
+    ///
+    /// The command must not be made a process group leader with
+    /// [`CommandExt::process_group`], as the intermediate process can't then start a new session,
+    /// and spawning fails with `EPERM`. The daemon is in a new process group anyway, as it is in a
+    /// new session.
+    pub fn spawn(self, command: &mut Command) -> Result<u32, DaemonError> {
+        // Check for collisions before changing the command at all.
+        validate_child_fds(&self.mappings)?;
+
+        let stdio = |fd: Option<OwnedFd>| -> io::Result<Stdio> {
+            Ok(match fd {
+                Some(fd) => fd.into(),
+                None => File::options()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/null")?
+                    .into(),
+            })
+        };
+        command
+            .stdin(stdio(self.stdin)?)
+            .stdout(stdio(self.stdout)?)
+            .stderr(stdio(self.stderr)?);
+
+        let (status_reader, status_writer) =
+            pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK).map_err(io::Error::from)?;
+        // Safety: `setsid`, `fork`, `write` and `_exit` will not allocate, so they are safe to call
+        // from this hook.
+        unsafe {
+            command.pre_exec(move || {
+                setsid()?;
+                match fork()? {
+                    ForkResult::Parent { child } => {
+                        // Report the daemon's PID, then exit without running any destructors or
+                        // exit handlers. The daemon continues with the rest of the spawn.
+                        let _ = write(&status_writer, &child.as_raw().to_ne_bytes());
+                        libc::_exit(0);
+                    }
+                    ForkResult::Child => Ok(()),
+                }
+            });
+        }
+        // These mappings are applied after the hook above, so in the daemon process.
+        command.fd_mappings(self.mappings)?;
+
+        // The daemon inherits the pipe on which `Command` reports errors, so `spawn` only returns
+        // once the daemon has exec'd the command or failed to. Waiting for the intermediate process
+        // then ensures that its report of the daemon's PID is available. If spawning failed then
+        // `Command` has already waited for it.
+        let spawn_result = command
+            .spawn()
+            .and_then(|mut intermediate| intermediate.wait().map(drop));
+
+        let mut buf = [0; size_of::<libc::pid_t>()];
+        let pid = match read(&status_reader, &mut buf) {
+            Ok(len) if len == buf.len() => Some(libc::pid_t::from_ne_bytes(buf) as u32),
+            _ => None,
+        };
+        match (spawn_result, pid) {
+            (Ok(()), Some(pid)) => Ok(pid),
+            (Ok(()), None) => {
+                Err(io::Error::other("Intermediate process didn't report daemon PID").into())
+            }
+            (Err(source), Some(pid)) => Err(DaemonError::Exec { pid, source }),
+            (Err(e), None) => Err(e.into()),
+        }
+    }
+}
+
+/// Spawns the given command as a detached daemon with the given FD mappings, and its stdin, stdout
+/// and stderr redirected to `/dev/null`. Returns the daemon's process ID once it has successfully
+/// exec'd the command.
+///
+/// See [`Daemon::spawn`].
+pub fn spawn_detached(command: &mut Command, mappings: Vec<FdMapping>) -> Result<u32, DaemonError> {
+    let mut daemon = Daemon::new();
+    daemon.fd_mappings(mappings);
+    daemon.spawn(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{
+        fs::read_to_string,
+        path::Path,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+    use tempfile::tempdir;
+
+    /// Waits for the given file to exist, for up to 10 seconds.
+    fn wait_for(path: &Path) {
+        let start = Instant::now();
+        while !path.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn detached_with_mappings() {
+        setup();
+        let dir = tempdir().unwrap();
+        let out_path = dir.path().join("out");
+        let done_path = dir.path().join("done");
+
+        let mut command = Command::new("sh");
+        command.current_dir(dir.path()).arg("-c").arg(
+            "echo $$; cut -d' ' -f6 /proc/$$/stat; cat <&3; test -t 0 || echo notty; \
+             cat; echo err >&2; touch done",
+        );
+        let mut daemon = Daemon::new();
+        let out = File::create(&out_path).unwrap();
+        daemon
+            .stdout(out.try_clone().unwrap())
+            .stderr(out)
+            .fd_mappings(vec![FdMapping {
+                parent_fd: File::open("testdata/file1.txt").unwrap().into(),
+                child_fd: 3,
+            }]);
+        let pid = daemon.spawn(&mut command).unwrap();
+
+        wait_for(&done_path);
+        let output = read_to_string(&out_path).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next().unwrap(), pid.to_string());
+        // The daemon is in a new session, but isn't its leader.
+        let sid: u32 = lines.next().unwrap().parse().unwrap();
+        assert_ne!(sid, pid);
+        assert_ne!(sid, nix::unistd::getsid(None).unwrap().as_raw() as u32);
+        assert_eq!(lines.next().unwrap(), "test 1notty");
+        assert_eq!(lines.next().unwrap(), "err");
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn exec_failure() {
+        setup();
+
+        let error = spawn_detached(&mut Command::new("/nonexistent/daemon"), vec![]).unwrap_err();
+        match error {
+            DaemonError::Exec { source, .. } => {
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            e => panic!("Unexpected error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn process_group_leader() {
+        setup();
+
+        let mut command = Command::new("true");
+        command.process_group(0);
+        match spawn_detached(&mut command, vec![]).unwrap_err() {
+            DaemonError::Io(e) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+            e => panic!("Unexpected error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn collision() {
+        setup();
+
+        let file = || File::open("testdata/file1.txt").unwrap().into();
+        assert!(matches!(
+            spawn_detached(
+                &mut Command::new("true"),
+                vec![
+                    FdMapping {
+                        parent_fd: file(),
+                        child_fd: 3,
+                    },
+                    FdMapping {
+                        parent_fd: file(),
+                        child_fd: 3,
+                    },
+                ],
+            ),
+            Err(DaemonError::Collision(_))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passing directories to a child process as capabilities, rather than as paths.
+//!
+//! The child can take them with [`take_dir`](crate::inherited::take_dir) and then access files
+//! within them with `openat` and friends, or as a `cap_std::fs::Dir` with
+//! `inherited::take_cap_std_dir` if the `cap-std` feature is enabled.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::dir::{map_dirs, open_dir, open_dir_path};
+//! use std::process::Command;
+//!
+//! let mut command = Command::new("worker");
+//! map_dirs(
+//!     &mut command,
+//!     [
+//!         ("data", open_dir("/srv/data").unwrap()),
+//!         ("cache", open_dir_path("/var/cache/worker").unwrap()),
+//!     ],
+//!     3,
+//! )
+//! .unwrap();
+//! let mut child = command.spawn().unwrap();
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, NamedFdMapping,
+    inherited::{FD_NAMES_ENV_VAR, format_fd_names, is_valid_fd_name},
+};
+use nix::{
+    fcntl::{OFlag, open},
+    sys::stat::Mode,
+};
+use std::{
+    io,
+    os::fd::{OwnedFd, RawFd},
+    path::Path,
+    process::Command,
+};
+
+/// Opens the directory at the given path for reading, with `O_CLOEXEC` set.
+///
+/// Fails if the path isn't a directory.
+pub fn open_dir(path: impl AsRef<Path>) -> io::Result<OwnedFd> {
+    Ok(open(
+        path.as_ref(),
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?)
+}
+
+/// Opens the directory at the given path with `O_PATH`, with `O_CLOEXEC` set.
+///
+/// The resulting FD can only be used as the base for `openat` and similar calls, not to list the
+/// directory, and doesn't need read permission on the directory itself. Fails if the path isn't a
+/// directory.
+pub fn open_dir_path(path: impl AsRef<Path>) -> io::Result<OwnedFd> {
+    Ok(open(
+        path.as_ref(),
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?)
+}
+
+/// Adds the given named directories to the command on consecutive file descriptors starting at
+/// `first_fd`, with their names in the [`FD_NAMES_ENV_VAR`] environment variable.
+///
+/// Names must not be empty or contain `:`, otherwise an error of kind
+/// [`io::ErrorKind::InvalidInput`] is returned and the command isn't changed. As with
+/// [`CommandFdExt::fd_mappings`], this shouldn't be combined with other mappings on the same
+/// command.
+pub fn map_dirs<N: Into<String>>(
+    command: &mut Command,
+    dirs: impl IntoIterator<Item = (N, OwnedFd)>,
+    first_fd: RawFd,
+) -> io::Result<&mut Command> {
+    let mappings: Vec<NamedFdMapping> = dirs
+        .into_iter()
+        .zip(first_fd..)
+        .map(|((name, parent_fd), child_fd)| NamedFdMapping {
+            name: Some(name.into()),
+            mapping: FdMapping {
+                parent_fd,
+                child_fd,
+            },
+        })
+        .collect();
+    if let Some(name) = mappings
+        .iter()
+        .filter_map(|named| named.name.as_deref())
+        .find(|name| !is_valid_fd_name(name))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid directory name {name:?}"),
+        ));
+    }
+    command.env(FD_NAMES_ENV_VAR, format_fd_names(&mappings));
+    Ok(command
+        .fd_mappings(mappings.into_iter().map(Into::into).collect())
+        .expect("Directory FDs should be distinct"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::fs::{File, create_dir};
+    use tempfile::tempdir;
+
+    #[test]
+    fn open() {
+        setup();
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("file");
+        File::create(&file_path).unwrap();
+
+        open_dir(dir.path()).unwrap();
+        open_dir_path(dir.path()).unwrap();
+        assert_eq!(
+            open_dir(&file_path).unwrap_err().raw_os_error(),
+            Some(nix::libc::ENOTDIR)
+        );
+        assert_eq!(
+            open_dir_path(&file_path).unwrap_err().raw_os_error(),
+            Some(nix::libc::ENOTDIR)
+        );
+    }
+
+    #[test]
+    fn map_to_child() {
+        setup();
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("data")).unwrap();
+        File::create(dir.path().join("data").join("file")).unwrap();
+        create_dir(dir.path().join("cache")).unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            "echo $COMMAND_FDS_NAMES; ls /proc/self/fd/3/; \
+             touch /proc/self/fd/4/new && echo created",
+        );
+        map_dirs(
+            &mut command,
+            [
+                ("data", open_dir(dir.path().join("data")).unwrap()),
+                ("cache", open_dir_path(dir.path().join("cache")).unwrap()),
+            ],
+            3,
+        )
+        .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"3=data:4=cache\nfile\ncreated\n");
+        assert!(dir.path().join("cache").join("new").exists());
+    }
+
+    #[test]
+    fn invalid_names() {
+        setup();
+        let dir = tempdir().unwrap();
+
+        for name in ["", "a:b"] {
+            let mut command = Command::new("true");
+            let error =
+                map_dirs(&mut command, [(name, open_dir(dir.path()).unwrap())], 3).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(command.get_envs().count(), 0);
+        }
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A store in a supervisor process where worker processes can keep file descriptors, so that they
+//! survive the worker crashing and are passed back to it when it is restarted, like systemd's
+//! `FDSTORE`.
+//!
+//! # Example
+//!
+//! In the supervisor:
+//!
+//! ```no_run
+//! use command_fds::fdstore::FdStore;
+//! use std::process::Command;
+//!
+//! let store = FdStore::new(3);
+//! loop {
+//!     // Any FDs stored by the previous instance of the worker are passed to the new one.
+//!     let mut worker = store.spawn(Command::new("worker")).unwrap();
+//!     let status = worker.wait().unwrap();
+//!     println!("Worker exited with {status}, restarting");
+//! }
+//! ```
+//!
+//! In the worker:
+//!
+//! ```no_run
+//! use command_fds::fdstore::FdStoreClient;
+//! use command_fds::inherited::{
+//!     InheritedFds, init_inherited_fds, take_named_fd_ownership, with_inherited_fds,
+//! };
+//! use std::net::TcpStream;
+//! use std::os::fd::AsFd;
+//!
+//! // SAFETY: This is called before anything else in the program.
+//! unsafe {
+//!     init_inherited_fds().unwrap();
+//! }
+//! with_inherited_fds(InheritedFds::set_names_from_env).unwrap().unwrap();
+//! let store = FdStoreClient::from_inherited().unwrap().unwrap();
+//!
+//! let connection = match take_named_fd_ownership("connection") {
+//!     Ok(fd) => TcpStream::from(fd),
+//!     Err(_) => {
+//!         let connection = TcpStream::connect("127.0.0.1:8080").unwrap();
+//!         store.store("connection", connection.as_fd()).unwrap();
+//!         connection
+//!     }
+//! };
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, NamedFdMapping,
+    inherited::{
+        FD_NAMES_ENV_VAR, InheritedFdError, InheritedFds, fd_from_parent_env, format_fd_names,
+        is_valid_fd_name, set_fd_env, with_inherited_fds,
+    },
+    transfer::{TransferError, recv_fds, send_fds},
+};
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::{Deref, DerefMut},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    process::{Child, Command, ExitStatus},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+use thiserror::Error;
+
+/// The environment variable used to tell a worker process which file descriptor its connection to
+/// the store is. See [`PARENT_PID_ENV_SUFFIX`](crate::inherited::PARENT_PID_ENV_SUFFIX) for how it
+/// is checked.
+pub const FD_STORE_ENV_VAR: &str = "COMMAND_FDS_STORE_FD";
+
+/// Errors adding a file descriptor to an [`FdStore`].
+#[derive(Debug, Error)]
+pub enum FdStoreError {
+    /// The name is empty or contains `:`, so can't be advertised to a worker
+    #[error("Invalid name {0:?} for stored FD")]
+    InvalidName(String),
+
+    /// Error sending the file descriptor to the store
+    #[error(transparent)]
+    Transfer(#[from] TransferError),
+}
+
+/// Checks that the given name can be advertised in the [`FD_NAMES_ENV_VAR`] environment variable.
+fn validate_name(name: &str) -> Result<(), FdStoreError> {
+    if !is_valid_fd_name(name) {
+        Err(FdStoreError::InvalidName(name.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// A store of named file descriptors kept on behalf of a worker process.
+///
+/// Cloning an `FdStore` gives another handle to the same store.
+#[derive(Clone, Debug)]
+pub struct FdStore {
+    fds: Arc<Mutex<BTreeMap<String, OwnedFd>>>,
+    first_fd: RawFd,
+}
+
+impl FdStore {
+    /// Creates a new empty store.
+    ///
+    /// When a worker is spawned, its connection to the store is mapped to `first_fd`, and the
+    /// stored file descriptors to consecutive file descriptors after it.
+    pub fn new(first_fd: RawFd) -> Self {
+        Self {
+            fds: Default::default(),
+            first_fd,
+        }
+    }
+
+    /// Adds the given file descriptor to the store, replacing and closing any previous file
+    /// descriptor with the same name.
+    ///
+    /// The name must not be empty or contain `:`.
+    pub fn insert(&self, name: String, fd: OwnedFd) -> Result<(), FdStoreError> {
+        validate_name(&name)?;
+        self.fds.lock().unwrap().insert(name, fd);
+        Ok(())
+    }
+
+    /// Removes the file descriptor with the given name from the store, if there is one.
+    pub fn remove(&self, name: &str) -> Option<OwnedFd> {
+        self.fds.lock().unwrap().remove(name)
+    }
+
+    /// Returns the names of all file descriptors in the store.
+    pub fn names(&self) -> Vec<String> {
+        self.fds.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns mappings for duplicates of all the file descriptors in the store, named with their
+    /// names in the store.
+    fn mappings(&self) -> io::Result<Vec<NamedFdMapping>> {
+        self.fds
+            .lock()
+            .unwrap()
+            .iter()
+            .zip(self.first_fd + 1..)
+            .map(|((name, fd), child_fd)| {
+                Ok(NamedFdMapping {
+                    name: Some(name.clone()),
+                    mapping: FdMapping {
+                        parent_fd: fd.try_clone()?,
+                        child_fd,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Spawns the given command as a worker using this store.
+    ///
+    /// The worker is passed a connection to the store, advertised in the [`FD_STORE_ENV_VAR`]
+    /// environment variable, and duplicates of all the file descriptors currently in the store,
+    /// named in the [`FD_NAMES_ENV_VAR`] environment variable. A thread is spawned to add any file
+    /// descriptors sent by the worker to the store.
+    ///
+    /// The file descriptors used by the store must not be used by any other mapping on the
+    /// command.
+    pub fn spawn(&self, mut command: Command) -> io::Result<FdStoreChild> {
+        let (socket, child_socket) = UnixStream::pair()?;
+        let stored = self.mappings()?;
+        set_fd_env(&mut command, FD_STORE_ENV_VAR, self.first_fd);
+        command.env(FD_NAMES_ENV_VAR, format_fd_names(&stored));
+        let mut mappings: Vec<FdMapping> = stored.into_iter().map(Into::into).collect();
+        mappings.push(FdMapping {
+            parent_fd: child_socket.into(),
+            child_fd: self.first_fd,
+        });
+        command
+            .fd_mappings(mappings)
+            .expect("Store FDs should be distinct");
+
+        let child = command.spawn()?;
+        // Close our copy of the worker's end of the socket, so that the receiver sees EOF when the
+        // worker exits.
+        drop(command);
+
+        let store = self.clone();
+        let receiver = thread::spawn(move || store.receive(&socket));
+        Ok(FdStoreChild {
+            child,
+            receiver: Some(receiver),
+        })
+    }
+
+    /// Adds all file descriptors sent over the given socket to the store, until it is closed.
+    ///
+    /// File descriptors with invalid names are closed rather than stored. [`FdStoreClient::store`]
+    /// checks names before sending, so these can only come from a misbehaving worker.
+    fn receive(&self, socket: &UnixStream) -> Result<(), TransferError> {
+        while let Some(received) = recv_fds(socket)? {
+            let mut fds = self.fds.lock().unwrap();
+            for (name, fd) in received {
+                if validate_name(&name).is_ok() {
+                    fds.insert(name, fd);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A worker process spawned by [`FdStore::spawn`].
+///
+/// This dereferences to the [`Child`], so it can be killed or otherwise used as usual.
+#[derive(Debug)]
+pub struct FdStoreChild {
+    child: Child,
+    receiver: Option<JoinHandle<Result<(), TransferError>>>,
+}
+
+impl FdStoreChild {
+    /// Waits for the worker to exit, and for all file descriptors it sent to be added to the store.
+    ///
+    /// This should be used rather than [`Child::wait`] before spawning a new worker, so that the
+    /// new worker gets all the file descriptors which the old one stored.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        if let Some(receiver) = self.receiver.take() {
+            // Errors receiving are most likely due to the worker crashing midway through sending,
+            // so they are ignored, as the FDs received before that are still stored.
+            let _ = receiver.join();
+        }
+        Ok(status)
+    }
+}
+
+impl Deref for FdStoreChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for FdStoreChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+/// A worker's connection to the [`FdStore`] in its supervisor.
+#[derive(Debug)]
+pub struct FdStoreClient {
+    socket: UnixStream,
+}
+
+impl FdStoreClient {
+    /// Takes the connection to the store from the process-wide registry of inherited file
+    /// descriptors.
+    ///
+    /// Returns `None` if this process wasn't spawned by [`FdStore::spawn`].
+    pub fn from_inherited() -> Result<Option<Self>, InheritedFdError> {
+        with_inherited_fds(Self::from_registry)?
+    }
+
+    /// Takes the connection to the store from the given registry of inherited file descriptors.
+    ///
+    /// Returns `None` if this process wasn't spawned by [`FdStore::spawn`], or its parent process
+    /// isn't the supervisor which spawned it.
+    pub fn from_registry(fds: &mut InheritedFds) -> Result<Option<Self>, InheritedFdError> {
+        let Some(raw_fd) = fd_from_parent_env(FD_STORE_ENV_VAR)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            socket: fds.take(raw_fd)?.into(),
+        }))
+    }
+
+    /// Sends a duplicate of the given file descriptor to the store with the given name, replacing
+    /// any file descriptor previously stored with the same name.
+    ///
+    /// The name must not be empty or contain `:`.
+    pub fn store(&self, name: &str, fd: BorrowedFd) -> Result<(), FdStoreError> {
+        validate_name(name)?;
+        Ok(send_fds(&self.socket, &[(name, fd)])?)
+    }
+}
+
+impl AsFd for FdStoreClient {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{
+        fs::File,
+        io::{Read, Seek, Write},
+    };
+    use tempfile::tempfile;
+
+    #[test]
+    fn stored_fds_passed_to_worker() {
+        setup();
+        let store = FdStore::new(5);
+        let mut file = tempfile().unwrap();
+        file.write_all(b"state").unwrap();
+        file.rewind().unwrap();
+        store.insert("state".to_owned(), file.into()).unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"[ "$COMMAND_FDS_STORE_FD" = 5 ] && [ "$COMMAND_FDS_NAMES" = 6=state ] && [ -S /proc/self/fd/5 ] && cat <&6"#,
+        );
+        command.stdout(std::process::Stdio::piped());
+        let mut worker = store.spawn(command).unwrap();
+        let mut output = String::new();
+        worker
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+
+        assert!(worker.wait().unwrap().success());
+        assert_eq!(output, "state");
+        // The store should still have the FD for the next worker.
+        assert_eq!(store.names(), vec!["state".to_owned()]);
+    }
+
+    #[test]
+    fn receive_from_worker() {
+        setup();
+        let store = FdStore::new(3);
+        store
+            .insert("old".to_owned(), tempfile().unwrap().into())
+            .unwrap();
+        let (worker_socket, socket) = UnixStream::pair().unwrap();
+        let client = FdStoreClient {
+            socket: worker_socket,
+        };
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        client.store("new", file.as_fd()).unwrap();
+        client.store("old", file.as_fd()).unwrap();
+        drop(client);
+        store.receive(&socket).unwrap();
+
+        assert_eq!(store.names(), vec!["new".to_owned(), "old".to_owned()]);
+        let mut contents = String::new();
+        File::from(store.remove("old").unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "test 1");
+    }
+
+    #[test]
+    fn invalid_names() {
+        setup();
+        let store = FdStore::new(3);
+        for name in ["", "a:b", ":"] {
+            assert!(matches!(
+                store.insert(name.to_owned(), tempfile().unwrap().into()),
+                Err(FdStoreError::InvalidName(invalid)) if invalid == name
+            ));
+        }
+
+        let (worker_socket, socket) = UnixStream::pair().unwrap();
+        let client = FdStoreClient {
+            socket: worker_socket,
+        };
+        let file = File::open("testdata/file1.txt").unwrap();
+        assert!(matches!(
+            client.store("bad:name", file.as_fd()),
+            Err(FdStoreError::InvalidName(_))
+        ));
+        // A worker bypassing the client's check can still send a bad name, but it isn't stored.
+        send_fds(
+            &client.socket,
+            &[("bad:name", file.as_fd()), ("good", file.as_fd())],
+        )
+        .unwrap();
+        drop(client);
+        store.receive(&socket).unwrap();
+
+        assert_eq!(store.names(), vec!["good".to_owned()]);
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An inetd-style server, which accepts connections on a listening socket and spawns a command for
+//! each one with the connection as its stdin and stdout.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::inetd::Inetd;
+//! use std::net::TcpListener;
+//! use std::process::Command;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:7000").unwrap();
+//! let mut server = Inetd::new(listener, || Command::new("cat"));
+//! server.max_connections(10);
+//! server.serve().unwrap();
+//! ```
+
+use crate::{CommandFdExt, FdMapping};
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+    sys::socket::{SockFlag, accept4},
+};
+use std::{
+    fmt::{self, Debug, Formatter},
+    io,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    process::{Command, ExitStatus},
+    sync::mpsc::{Receiver, Sender, channel},
+    thread,
+};
+
+/// The command to run for each connection, and how to pass the connection to it.
+struct Spawner {
+    make_command: Box<dyn FnMut() -> Command + Send>,
+    max_connections: Option<usize>,
+    map_stderr: bool,
+}
+
+impl Spawner {
+    fn new(make_command: impl FnMut() -> Command + Send + 'static) -> Self {
+        Self {
+            make_command: Box::new(make_command),
+            max_connections: None,
+            map_stderr: false,
+        }
+    }
+
+    /// Returns whether another child can be spawned while `running` are already running.
+    fn has_capacity(&self, running: usize) -> bool {
+        self.max_connections.is_none_or(|max| running < max)
+    }
+
+    /// Builds the command for the given connection, with it mapped to stdin and stdout and
+    /// optionally stderr.
+    fn command(&mut self, connection: OwnedFd) -> io::Result<Command> {
+        let mut mappings = vec![FdMapping {
+            parent_fd: connection.try_clone()?,
+            child_fd: 1,
+        }];
+        if self.map_stderr {
+            mappings.push(FdMapping {
+                parent_fd: connection.try_clone()?,
+                child_fd: 2,
+            });
+        }
+        mappings.push(FdMapping {
+            parent_fd: connection,
+            child_fd: 0,
+        });
+        let mut command = (self.make_command)();
+        command.fd_mappings(mappings).map_err(io::Error::other)?;
+        Ok(command)
+    }
+}
+
+impl Debug for Spawner {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Spawner")
+            .field("max_connections", &self.max_connections)
+            .field("map_stderr", &self.map_stderr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Accepts a connection on the given listener with `SOCK_CLOEXEC` set, retrying on transient
+/// errors.
+///
+/// If the listener is non-blocking and there are no pending connections then this returns an error
+/// of kind [`io::ErrorKind::WouldBlock`].
+fn accept(listener: &OwnedFd) -> io::Result<OwnedFd> {
+    loop {
+        match accept4(listener.as_raw_fd(), SockFlag::SOCK_CLOEXEC) {
+            // Safety: `accept4` just returned this FD, so we own it.
+            Ok(fd) => return Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+            Err(Errno::EINTR | Errno::ECONNABORTED) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// A blocking inetd-style server.
+///
+/// Each child process is waited for on a separate thread, so that it is reaped as soon as it
+/// exits.
+#[derive(Debug)]
+pub struct Inetd {
+    listener: OwnedFd,
+    spawner: Spawner,
+    running: usize,
+    exits_sender: Sender<(u32, io::Result<ExitStatus>)>,
+    exits: Receiver<(u32, io::Result<ExitStatus>)>,
+    exited: Vec<(u32, io::Result<ExitStatus>)>,
+}
+
+impl Inetd {
+    /// Creates a new server accepting connections on the given listener, and running a command
+    /// returned by `make_command` for each one.
+    pub fn new(
+        listener: impl Into<OwnedFd>,
+        make_command: impl FnMut() -> Command + Send + 'static,
+    ) -> Self {
+        let (exits_sender, exits) = channel();
+        Self {
+            listener: listener.into(),
+            spawner: Spawner::new(make_command),
+            running: 0,
+            exits_sender,
+            exits,
+            exited: Vec::new(),
+        }
+    }
+
+    /// Sets the maximum number of child processes to run at once. Once this many are running, no
+    /// more connections are accepted until one exits.
+    pub fn max_connections(&mut self, max_connections: usize) -> &mut Self {
+        self.spawner.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Sets whether the connection should also be passed to child processes as their stderr. By
+    /// default stderr is inherited from the current process.
+    pub fn map_stderr(&mut self, map_stderr: bool) -> &mut Self {
+        self.spawner.map_stderr = map_stderr;
+        self
+    }
+
+    /// Returns the number of child processes currently running.
+    pub fn running(&self) -> usize {
+        self.running
+    }
+
+    /// Returns the process IDs and exit statuses of child processes which have exited since the
+    /// last call, without blocking.
+    pub fn reap(&mut self) -> Vec<(u32, io::Result<ExitStatus>)> {
+        self.collect_exits();
+        std::mem::take(&mut self.exited)
+    }
+
+    /// Waits until there is capacity for another child process, then accepts a single connection
+    /// and spawns a child process for it. Returns the PID of the child.
+    pub fn accept_one(&mut self) -> io::Result<u32> {
+        let connection = self.accept_connection()?;
+        self.spawn(connection)
+    }
+
+    /// Accepts connections and spawns child processes for them forever.
+    ///
+    /// Errors spawning a child for a single connection are ignored, and the connection is closed.
+    /// Errors accepting connections are returned.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let connection = self.accept_connection()?;
+            let _ = self.spawn(connection);
+        }
+    }
+
+    /// Waits until there is capacity for another child process, then accepts a single connection.
+    fn accept_connection(&mut self) -> io::Result<OwnedFd> {
+        self.collect_exits();
+        while !self.spawner.has_capacity(self.running) {
+            let exit = self.exits.recv().map_err(io::Error::other)?;
+            self.record_exit(exit);
+        }
+
+        loop {
+            match accept(&self.listener) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut poll_fds = [PollFd::new(self.listener.as_fd(), PollFlags::POLLIN)];
+                    match poll(&mut poll_fds, PollTimeout::NONE) {
+                        Ok(_) | Err(Errno::EINTR) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Spawns a child process for the given connection, and a thread to wait for it.
+    fn spawn(&mut self, connection: OwnedFd) -> io::Result<u32> {
+        let mut child = self.spawner.command(connection)?.spawn()?;
+        let pid = child.id();
+        let sender = self.exits_sender.clone();
+        self.running += 1;
+        thread::spawn(move || {
+            let _ = sender.send((pid, child.wait()));
+        });
+        Ok(pid)
+    }
+
+    fn collect_exits(&mut self) {
+        while let Ok(exit) = self.exits.try_recv() {
+            self.record_exit(exit);
+        }
+    }
+
+    fn record_exit(&mut self, exit: (u32, io::Result<ExitStatus>)) {
+        self.running -= 1;
+        self.exited.push(exit);
+    }
+}
+
+/// An asynchronous inetd-style server for use with tokio.
+#[cfg(feature = "tokio")]
+pub mod tokio {
+    use super::{Spawner, accept};
+    use nix::fcntl::{FcntlArg, OFlag, fcntl};
+    use std::{
+        io,
+        os::fd::OwnedFd,
+        process::{Command, ExitStatus},
+    };
+    use tokio::{
+        io::unix::AsyncFd,
+        sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    };
+
+    /// An asynchronous inetd-style server.
+    ///
+    /// Each child process is waited for in a separate task, so that it is reaped as soon as it
+    /// exits. This must be used within a tokio runtime.
+    ///
+    /// See [`Inetd`](super::Inetd).
+    #[derive(Debug)]
+    pub struct Inetd {
+        listener: AsyncFd<OwnedFd>,
+        spawner: Spawner,
+        running: usize,
+        exits_sender: UnboundedSender<(u32, io::Result<ExitStatus>)>,
+        exits: UnboundedReceiver<(u32, io::Result<ExitStatus>)>,
+        exited: Vec<(u32, io::Result<ExitStatus>)>,
+    }
+
+    impl Inetd {
+        /// Creates a new server accepting connections on the given listener, and running a command
+        /// returned by `make_command` for each one.
+        ///
+        /// The listener is set to non-blocking mode.
+        pub fn new(
+            listener: impl Into<OwnedFd>,
+            make_command: impl FnMut() -> Command + Send + 'static,
+        ) -> io::Result<Self> {
+            let listener = listener.into();
+            let flags = OFlag::from_bits_retain(fcntl(&listener, FcntlArg::F_GETFL)?);
+            fcntl(&listener, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+            let (exits_sender, exits) = unbounded_channel();
+            Ok(Self {
+                listener: AsyncFd::new(listener)?,
+                spawner: Spawner::new(make_command),
+                running: 0,
+                exits_sender,
+                exits,
+                exited: Vec::new(),
+            })
+        }
+
+        /// Sets the maximum number of child processes to run at once.
+        ///
+        /// See [`Inetd::max_connections`](super::Inetd::max_connections).
+        pub fn max_connections(&mut self, max_connections: usize) -> &mut Self {
+            self.spawner.max_connections = Some(max_connections);
+            self
+        }
+
+        /// Sets whether the connection should also be passed to child processes as their stderr.
+        pub fn map_stderr(&mut self, map_stderr: bool) -> &mut Self {
+            self.spawner.map_stderr = map_stderr;
+            self
+        }
+
+        /// Returns the number of child processes currently running.
+        pub fn running(&self) -> usize {
+            self.running
+        }
+
+        /// Returns the process IDs and exit statuses of child processes which have exited since
+        /// the last call, without blocking.
+        pub fn reap(&mut self) -> Vec<(u32, io::Result<ExitStatus>)> {
+            self.collect_exits();
+            std::mem::take(&mut self.exited)
+        }
+
+        /// Waits until there is capacity for another child process, then accepts a single
+        /// connection and spawns a child process for it. Returns the PID of the child.
+        pub async fn accept_one(&mut self) -> io::Result<u32> {
+            let connection = self.accept_connection().await?;
+            self.spawn(connection)
+        }
+
+        /// Accepts connections and spawns child processes for them forever.
+        ///
+        /// See [`Inetd::serve`](super::Inetd::serve).
+        pub async fn serve(&mut self) -> io::Result<()> {
+            loop {
+                let connection = self.accept_connection().await?;
+                let _ = self.spawn(connection);
+            }
+        }
+
+        /// Waits until there is capacity for another child process, then accepts a single
+        /// connection.
+        async fn accept_connection(&mut self) -> io::Result<OwnedFd> {
+            self.collect_exits();
+            while !self.spawner.has_capacity(self.running) {
+                let exit = self
+                    .exits
+                    .recv()
+                    .await
+                    .ok_or_else(|| io::Error::other("Exit channel closed"))?;
+                self.record_exit(exit);
+            }
+
+            loop {
+                let mut guard = self.listener.readable().await?;
+                if let Ok(result) = guard.try_io(|listener| accept(listener.get_ref())) {
+                    return result;
+                }
+            }
+        }
+
+        /// Spawns a child process for the given connection, and a task to wait for it.
+        fn spawn(&mut self, connection: OwnedFd) -> io::Result<u32> {
+            let command = self.spawner.command(connection)?;
+            let mut child = tokio::process::Command::from(command).spawn()?;
+            let pid = child
+                .id()
+                .ok_or_else(|| io::Error::other("Child has no PID"))?;
+            let sender = self.exits_sender.clone();
+            self.running += 1;
+            tokio::spawn(async move {
+                let _ = sender.send((pid, child.wait().await));
+            });
+            Ok(pid)
+        }
+
+        fn collect_exits(&mut self) {
+            while let Ok(exit) = self.exits.try_recv() {
+                self.record_exit(exit);
+            }
+        }
+
+        fn record_exit(&mut self, exit: (u32, io::Result<ExitStatus>)) {
+            self.running -= 1;
+            self.exited.push(exit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{
+        io::{Read, Write},
+        net::Shutdown,
+        os::unix::net::{UnixListener, UnixStream},
+    };
+    use tempfile::TempDir;
+
+    fn listener() -> (TempDir, UnixListener) {
+        let dir = TempDir::new().unwrap();
+        let listener = UnixListener::bind(dir.path().join("sock")).unwrap();
+        (dir, listener)
+    }
+
+    fn sh_command(script: &'static str) -> impl FnMut() -> Command + Send + 'static {
+        move || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        }
+    }
+
+    #[test]
+    fn connection_on_stdio() {
+        setup();
+        let (dir, listener) = listener();
+        let mut server = Inetd::new(listener, sh_command("tr a-z A-Z; echo error >&2"));
+        server.map_stderr(true);
+
+        let mut client = UnixStream::connect(dir.path().join("sock")).unwrap();
+        let pid = server.accept_one().unwrap();
+        client.write_all(b"hello\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "HELLO\nerror\n");
+
+        let exits = loop {
+            let exits = server.reap();
+            if !exits.is_empty() {
+                break exits;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(exits.len(), 1);
+        assert_eq!(exits[0].0, pid);
+        assert!(exits[0].1.as_ref().unwrap().success());
+        assert_eq!(server.running(), 0);
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        setup();
+        let (dir, listener) = listener();
+        let mut server = Inetd::new(listener, sh_command("read line; echo $line"));
+        server.max_connections(1);
+
+        let mut first = UnixStream::connect(dir.path().join("sock")).unwrap();
+        let mut second = UnixStream::connect(dir.path().join("sock")).unwrap();
+        server.accept_one().unwrap();
+        assert_eq!(server.running(), 1);
+
+        let handle = thread::spawn(move || {
+            server.accept_one().unwrap();
+            server
+        });
+        // The second connection isn't accepted until the first child exits.
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        first.write_all(b"first\n").unwrap();
+        let mut server = handle.join().unwrap();
+        assert_eq!(server.running(), 1);
+        assert_eq!(server.reap().len(), 1);
+
+        second.write_all(b"second\n").unwrap();
+        let mut output = String::new();
+        second.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "second\n");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[::tokio::test(crate = "::tokio")]
+    async fn tokio_connection_on_stdio() {
+        setup();
+        let (dir, listener) = listener();
+        let mut server = super::tokio::Inetd::new(listener, sh_command("tr a-z A-Z")).unwrap();
+        server.max_connections(1);
+
+        let mut client = UnixStream::connect(dir.path().join("sock")).unwrap();
+        server.accept_one().await.unwrap();
+        client.write_all(b"hello\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "HELLO\n");
+
+        let second = UnixStream::connect(dir.path().join("sock")).unwrap();
+        server.accept_one().await.unwrap();
+        second.shutdown(Shutdown::Write).unwrap();
+        let exits = server.reap();
+        assert_eq!(exits.len(), 1);
+        assert!(exits[0].1.as_ref().unwrap().success());
+    }
+}
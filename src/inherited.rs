@@ -14,22 +14,69 @@
 
 //! Utilities for safely obtaining `OwnedFd`s for inherited file descriptors.
 
+use crate::{
+    FdMapping, NamedFdMapping,
+    jobserver::{Jobserver, JobserverAuth, MAKEFLAGS_ENV_VARS, parse_makeflags},
+    protocol::FdProtocol,
+    transfer::{TransferError, recv_inherited_fds},
+};
 use nix::{
-    fcntl::{F_SETFD, FdFlag, fcntl},
+    errno::Errno,
+    fcntl::{F_GETFL, F_SETFD, FdFlag, OFlag, fcntl},
     libc,
+    sys::{
+        socket::{SockaddrStorage, getsockname},
+        stat::{SFlag, fstat},
+    },
+    unistd::{Pid, getpid, getppid},
 };
 use std::{
-    collections::HashMap,
-    fs::{canonicalize, read_dir},
-    os::fd::{FromRawFd, OwnedFd, RawFd},
-    sync::{Mutex, OnceLock},
+    collections::BTreeMap,
+    env::VarError,
+    fs::{canonicalize, read_dir, read_link, read_to_string},
+    ops::RangeInclusive,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    thread::{self, JoinHandle},
 };
 use thiserror::Error;
 
-static INHERITED_FDS: OnceLock<Mutex<HashMap<RawFd, Option<OwnedFd>>>> = OnceLock::new();
+static INHERITED_FDS: Mutex<Option<InheritedFds>> = Mutex::new(None);
+
+/// The environment variable used to advertise the names of file descriptors passed to a child
+/// process, as a `:`-separated list of `fd=name` pairs. See [`InheritedFds::set_names_from_env`].
+pub const FD_NAMES_ENV_VAR: &str = "COMMAND_FDS_NAMES";
+
+/// The suffix added to the name of an environment variable advertising a single file descriptor,
+/// such as [`CONTROL_FD_ENV_VAR`], to name the variable holding the PID of the process which set
+/// it.
+///
+/// A child process only uses the advertised file descriptor if this PID is its parent's, or the PID
+/// variable isn't set. So if the variables are inherited by its own children, they don't mistake
+/// some unrelated file descriptor for the advertised one.
+pub const PARENT_PID_ENV_SUFFIX: &str = "_PARENT_PID";
+
+/// The environment variable used to tell a child process spawned by
+/// [`spawn_with_control`](crate::control::spawn_with_control) which file descriptor its control
+/// socket is. See [`PARENT_PID_ENV_SUFFIX`] for how it is checked.
+pub const CONTROL_FD_ENV_VAR: &str = "COMMAND_FDS_CONTROL_FD";
+
+/// The prefix of the environment variables used by Android init to advertise sockets passed to a
+/// service. See [`android_socket_env_var`].
+pub const ANDROID_SOCKET_ENV_PREFIX: &str = "ANDROID_SOCKET_";
+
+/// The prefix of the environment variables used by Android init to advertise files passed to a
+/// service. See [`android_file_env_var`].
+pub const ANDROID_FILE_ENV_PREFIX: &str = "ANDROID_FILE_";
 
 /// Errors that can occur while taking an ownership of `RawFd`
 #[derive(Debug, PartialEq, Error)]
+#[non_exhaustive]
 pub enum InheritedFdError {
     /// init_inherited_fds() not called
     #[error("init_inherited_fds() not called")]
@@ -42,6 +89,144 @@ pub enum InheritedFdError {
     /// Not an inherited file descriptor
     #[error("FD {0} is either invalid file descriptor or not an inherited one")]
     FileDescriptorNotInherited(RawFd),
+
+    /// Environment variable describing inherited file descriptors is invalid
+    #[error("Invalid value for environment variable {0}")]
+    InvalidEnvVar(String),
+
+    /// No inherited file descriptor with the given name
+    #[error("No inherited FD named {0:?}")]
+    NameNotFound(String),
+
+    /// Inherited file descriptors were not claimed
+    #[error("Inherited FDs {0:?} were not claimed")]
+    UnclaimedFds(Vec<RawFd>),
+
+    /// Querying metadata about an inherited file descriptor failed
+    #[error("Failed to query metadata of FD {0}: {1}")]
+    QueryFailed(RawFd, Errno),
+
+    /// Opening a file advertised to this process failed
+    #[error("Failed to open {0:?}: {1}")]
+    OpenFailed(PathBuf, Errno),
+
+    /// Inherited file descriptor doesn't refer to what it was advertised as
+    #[error("FD {0} is not the expected {1}")]
+    UnexpectedFd(RawFd, String),
+}
+
+/// The type of file that a file descriptor refers to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FdFileType {
+    /// A regular file.
+    RegularFile,
+    /// A directory.
+    Directory,
+    /// A symbolic link, e.g. opened with `O_PATH | O_NOFOLLOW`.
+    Symlink,
+    /// A character device, such as a terminal or `/dev/null`.
+    CharacterDevice,
+    /// A block device.
+    BlockDevice,
+    /// A pipe or FIFO.
+    Fifo,
+    /// A socket.
+    Socket,
+    /// Some other type of file, such as an eventfd or other anonymous inode.
+    Unknown,
+}
+
+/// The access mode with which a file descriptor was opened.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FdAccessMode {
+    /// Opened with `O_RDONLY`.
+    ReadOnly,
+    /// Opened with `O_WRONLY`.
+    WriteOnly,
+    /// Opened with `O_RDWR`.
+    ReadWrite,
+    /// Opened with `O_PATH`, so neither reading nor writing is possible.
+    Path,
+}
+
+/// Metadata about an open file descriptor.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FdMetadata {
+    /// The type of file which the FD refers to.
+    pub file_type: FdFileType,
+    /// The target of the FD's `/proc/self/fd` link. This is a filesystem path for files, or
+    /// something like `socket:[1234]` or `pipe:[1234]` for other types.
+    pub path: Option<PathBuf>,
+    /// The local address of the socket, if the FD is a socket.
+    pub socket_address: Option<String>,
+    /// The access mode with which the FD was opened.
+    pub access_mode: FdAccessMode,
+    /// Whether the `O_NONBLOCK` flag is set.
+    pub nonblocking: bool,
+    /// Whether the `O_APPEND` flag is set.
+    pub append: bool,
+    /// The current file offset, from `/proc/self/fdinfo`.
+    pub position: Option<u64>,
+}
+
+/// Information about an entry in the registry of inherited file descriptors.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InheritedFdInfo {
+    /// The file descriptor number.
+    pub fd: RawFd,
+    /// The name of the FD, if it has one.
+    pub name: Option<String>,
+    /// Whether ownership of the FD has already been taken.
+    pub taken: bool,
+    /// Metadata about the FD. This is `None` if ownership has already been taken, as the FD may
+    /// since have been closed or reused for something else.
+    pub metadata: Option<FdMetadata>,
+}
+
+/// Options controlling which file descriptors [`init_inherited_fds_with_options`] takes ownership
+/// of, and what it does with them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InitOptions {
+    /// Ranges of file descriptors to take ownership of. Open file descriptors outside all of these
+    /// ranges are left alone.
+    pub include: Vec<RangeInclusive<RawFd>>,
+    /// Ranges of file descriptors not to take ownership of, even if they are in one of the
+    /// `include` ranges. This can be used for file descriptors opened by a preloaded library, for
+    /// example.
+    pub exclude: Vec<RangeInclusive<RawFd>>,
+    /// Whether to also take ownership of standard input/output/error, if they are in one of the
+    /// `include` ranges. This is useful for inetd-style programs which are passed a socket as
+    /// stdin, but note that the Rust runtime will still use these file descriptors for `stdin()`
+    /// and friends, so they should not be closed while that may happen.
+    pub include_stdio: bool,
+    /// Whether to set the `FD_CLOEXEC` flag on the file descriptors taken. This can be disabled if
+    /// some of them are to be passed on unchanged to a child process.
+    pub set_cloexec: bool,
+    /// The root of the proc filesystem, in which `self/fd` is read to find open file descriptors.
+    pub proc_root: PathBuf,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            include: vec![0..=RawFd::MAX],
+            exclude: Vec::new(),
+            include_stdio: false,
+            set_cloexec: true,
+            proc_root: PathBuf::from("/proc"),
+        }
+    }
+}
+
+impl InitOptions {
+    /// Returns whether the given file descriptor should be taken according to these options.
+    fn should_take(&self, raw_fd: RawFd) -> bool {
+        let is_stdio =
+            [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].contains(&raw_fd);
+        (self.include_stdio || !is_stdio)
+            && self.include.iter().any(|range| range.contains(&raw_fd))
+            && !self.exclude.iter().any(|range| range.contains(&raw_fd))
+    }
 }
 
 /// Takes ownership of all open file descriptors in this process other than standard
@@ -49,84 +234,848 @@ pub enum InheritedFdError {
 ///
 /// Sets the `FD_CLOEXEC` flag on all of these file descriptors.
 ///
+/// This is equivalent to calling [`init_inherited_fds_with_options`] with the default options.
+///
 /// # Safety
 ///
 /// This must be called very early in the program, before the ownership of any file descriptors
 /// (except stdin/out/err) is taken.
 pub unsafe fn init_inherited_fds() -> Result<(), std::io::Error> {
-    let mut fds = HashMap::new();
+    // SAFETY: Our caller guarantees the same requirements.
+    unsafe { init_inherited_fds_with_options(&InitOptions::default()) }
+}
+
+/// Takes ownership of the open file descriptors in this process selected by the given options, so
+/// that they can later be obtained by calling [`take_fd_ownership`].
+///
+/// # Safety
+///
+/// This must be called very early in the program, before the ownership of any of the file
+/// descriptors selected by `options` is taken.
+pub unsafe fn init_inherited_fds_with_options(options: &InitOptions) -> Result<(), std::io::Error> {
+    let mut inherited_fds = INHERITED_FDS.lock().unwrap();
+    // Check this before taking ownership of anything, as otherwise the FDs would be closed again
+    // when the new registry was dropped.
+    if inherited_fds.is_some() {
+        return Err(std::io::Error::other(
+            "Inherited fds were already initialized",
+        ));
+    }
+
+    // SAFETY: Our caller guarantees the same requirements.
+    *inherited_fds = Some(unsafe { InheritedFds::from_open_fds(options)? });
+    Ok(())
+}
+
+/// Calls the given function with the process-wide registry of inherited file descriptors, which
+/// is set up by [`init_inherited_fds`].
+///
+/// This can be used to call any method of [`InheritedFds`] on the process-wide registry.
+pub fn with_inherited_fds<T>(
+    f: impl FnOnce(&mut InheritedFds) -> T,
+) -> Result<T, InheritedFdError> {
+    let mut inherited_fds = INHERITED_FDS.lock().unwrap();
+    let fds = inherited_fds
+        .as_mut()
+        .ok_or(InheritedFdError::NotInitialized)?;
+    Ok(f(fds))
+}
+
+/// Takes the ownership of the given `RawFd` and returns an `OwnedFd` for it.
+///
+/// The returned FD will have the `FD_CLOEXEC` flag set.
+///
+/// An error is returned when the ownership was already taken (by a prior call to this
+/// function with the same `RawFd`) or `RawFd` is not an inherited file descriptor.
+pub fn take_fd_ownership(raw_fd: RawFd) -> Result<OwnedFd, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take(raw_fd))?
+}
+
+/// Takes the ownership of the lowest-numbered unclaimed inherited file descriptor with the given
+/// name.
+///
+/// See [`InheritedFds::take_by_name`].
+pub fn take_named_fd_ownership(name: &str) -> Result<OwnedFd, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take_by_name(name))?
+}
+
+/// Takes ownership of all inherited file descriptors which haven't yet been claimed by
+/// [`take_fd_ownership`], sorted by FD number.
+///
+/// This is intended to be called once the program has finished starting up and taken all the
+/// file descriptors it expects, so that any others can be inspected or closed.
+pub fn take_unclaimed_fds() -> Result<Vec<OwnedFd>, InheritedFdError> {
+    with_inherited_fds(InheritedFds::take_unclaimed)
+}
+
+/// Closes all inherited file descriptors which haven't yet been claimed by [`take_fd_ownership`],
+/// and returns the FD numbers which were closed.
+pub fn close_unclaimed_fds() -> Result<Vec<RawFd>, InheritedFdError> {
+    with_inherited_fds(InheritedFds::close_unclaimed)
+}
+
+/// Closes all inherited file descriptors which haven't yet been claimed by [`take_fd_ownership`],
+/// and returns an error listing them if there were any.
+///
+/// This can be used to catch parent processes which pass file descriptors that the program doesn't
+/// expect.
+pub fn ensure_all_fds_claimed() -> Result<(), InheritedFdError> {
+    with_inherited_fds(InheritedFds::ensure_all_claimed)?
+}
+
+/// Returns information about all file descriptors which were inherited, sorted by FD number.
+///
+/// This doesn't take ownership of any of the file descriptors, so it can be used to log what the
+/// parent process passed before deciding what to do with them.
+pub fn inherited_fds_info() -> Result<Vec<InheritedFdInfo>, InheritedFdError> {
+    with_inherited_fds(|fds| fds.info())?
+}
+
+/// Takes the control socket passed by [`spawn_with_control`](crate::control::spawn_with_control)
+/// from the process-wide registry of inherited file descriptors.
+///
+/// See [`InheritedFds::take_control_socket`].
+pub fn take_control_socket() -> Result<Option<UnixStream>, InheritedFdError> {
+    with_inherited_fds(InheritedFds::take_control_socket)?
+}
+
+/// Takes the control socket passed by [`spawn_with_control`](crate::control::spawn_with_control)
+/// from the process-wide registry of inherited file descriptors, and spawns a thread to receive
+/// file descriptors sent over it and add them to the registry.
+///
+/// The thread finishes when the parent process closes the control socket, or an error occurs.
+/// Returns `None` if this process wasn't passed a control socket.
+pub fn spawn_late_fd_receiver()
+-> Result<Option<JoinHandle<Result<(), TransferError>>>, InheritedFdError> {
+    let Some(socket) = take_control_socket()? else {
+        return Ok(None);
+    };
+    Ok(Some(thread::spawn(move || {
+        while recv_inherited_fds(&socket)?.is_some() {}
+        Ok(())
+    })))
+}
+
+/// Takes the socket with the given name passed by Android init, or by
+/// [`AndroidFds`](crate::android::AndroidFds), from the process-wide registry of inherited file
+/// descriptors.
+///
+/// This is equivalent to `android_get_control_socket`. See [`InheritedFds::take_android_socket`].
+pub fn take_android_socket(name: &str) -> Result<OwnedFd, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take_android_socket(name))?
+}
+
+/// Takes the file with the given path passed by Android init, or by
+/// [`AndroidFds`](crate::android::AndroidFds), from the process-wide registry of inherited file
+/// descriptors.
+///
+/// This is equivalent to `android_get_control_file`. See [`InheritedFds::take_android_file`].
+pub fn take_android_file(path: impl AsRef<Path>) -> Result<OwnedFd, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take_android_file(path))?
+}
+
+/// Takes the directory with the given name from the process-wide registry of inherited file
+/// descriptors.
+///
+/// See [`InheritedFds::take_dir`].
+pub fn take_dir(name: &str) -> Result<OwnedFd, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take_dir(name))?
+}
+
+/// Takes the directory with the given name from the process-wide registry of inherited file
+/// descriptors, as a [`cap_std::fs::Dir`].
+///
+/// See [`InheritedFds::take_cap_std_dir`].
+#[cfg(feature = "cap-std")]
+pub fn take_cap_std_dir(name: &str) -> Result<cap_std::fs::Dir, InheritedFdError> {
+    with_inherited_fds(|fds| fds.take_cap_std_dir(name))?
+}
+
+/// Takes the jobserver advertised in the make flags environment variables from the process-wide
+/// registry of inherited file descriptors.
+///
+/// See [`InheritedFds::take_jobserver`].
+pub fn take_jobserver() -> Result<Option<Jobserver>, InheritedFdError> {
+    with_inherited_fds(InheritedFds::take_jobserver)?
+}
+
+/// Returns the name of the environment variable used by Android init to advertise the socket with
+/// the given name.
+pub fn android_socket_env_var(name: &str) -> String {
+    format!("{ANDROID_SOCKET_ENV_PREFIX}{name}")
+}
+
+/// Returns the name of the environment variable used by Android init to advertise the file with
+/// the given path. Every character of the path other than ASCII letters and digits is replaced with
+/// `_`.
+pub fn android_file_env_var(path: impl AsRef<Path>) -> String {
+    let escaped = path
+        .as_ref()
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .map(|&c| {
+            if c.is_ascii_alphanumeric() {
+                c as char
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("{ANDROID_FILE_ENV_PREFIX}{escaped}")
+}
+
+/// Names file descriptors in the process-wide registry according to the given protocol, and returns
+/// the file descriptors which the protocol advertised in order.
+///
+/// See [`InheritedFds::apply_protocol`].
+pub fn apply_fd_protocol(protocol: &impl FdProtocol) -> Result<Vec<RawFd>, InheritedFdError> {
+    with_inherited_fds(|fds| fds.apply_protocol(protocol))?
+}
+
+/// Parses the value of the [`FD_NAMES_ENV_VAR`] environment variable into pairs of file
+/// descriptor numbers and names.
+pub(crate) fn parse_fd_names(value: &str) -> Result<Vec<(RawFd, String)>, InheritedFdError> {
+    value
+        .split(':')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .and_then(|(raw_fd, name)| Some((raw_fd.parse().ok()?, name.to_owned())))
+                .ok_or_else(|| InheritedFdError::InvalidEnvVar(FD_NAMES_ENV_VAR.to_owned()))
+        })
+        .collect()
+}
+
+/// Parses a file descriptor number from the given environment variable, if it is set.
+pub(crate) fn fd_from_env(var: &str) -> Result<Option<RawFd>, InheritedFdError> {
+    match std::env::var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| InheritedFdError::InvalidEnvVar(var.to_owned())),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(InheritedFdError::InvalidEnvVar(var.to_owned())),
+    }
+}
+
+/// Returns the name of the environment variable which holds the PID of the process that set the
+/// given file descriptor environment variable with [`set_fd_env`].
+fn parent_pid_env_var(var: &str) -> String {
+    format!("{var}{PARENT_PID_ENV_SUFFIX}")
+}
+
+/// Sets the given environment variable on the command to tell the child process which file
+/// descriptor to use, along with the PID of this process as described for
+/// [`PARENT_PID_ENV_SUFFIX`].
+pub(crate) fn set_fd_env(command: &mut Command, var: &str, raw_fd: RawFd) {
+    command
+        .env(var, raw_fd.to_string())
+        .env(parent_pid_env_var(var), getpid().to_string());
+}
+
+/// Parses a file descriptor number set by [`set_fd_env`] from the given environment variable, if
+/// it is set and is meant for this process.
+pub(crate) fn fd_from_parent_env(var: &str) -> Result<Option<RawFd>, InheritedFdError> {
+    fd_from_parent_env_with(
+        var,
+        &|var| std::env::var_os(var).map(|value| value.to_string_lossy().into_owned()),
+        getppid(),
+    )
+}
+
+/// Like [`fd_from_parent_env`], but with the given environment and parent PID.
+fn fd_from_parent_env_with(
+    var: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+    parent_pid: Pid,
+) -> Result<Option<RawFd>, InheritedFdError> {
+    let Some(value) = env(var) else {
+        return Ok(None);
+    };
+    let pid_var = parent_pid_env_var(var);
+    if let Some(pid) = env(&pid_var) {
+        let pid = pid
+            .parse()
+            .map_err(|_| InheritedFdError::InvalidEnvVar(pid_var))?;
+        if Pid::from_raw(pid) != parent_pid {
+            return Ok(None);
+        }
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| InheritedFdError::InvalidEnvVar(var.to_owned()))
+}
+
+/// An entry in an [`InheritedFds`] registry.
+#[derive(Debug)]
+struct Entry {
+    /// The file descriptor, or `None` if ownership has already been taken.
+    fd: Option<OwnedFd>,
+    name: Option<String>,
+}
+
+impl Entry {
+    /// Takes the file descriptor if it hasn't already been taken, and returns a mapping to pass it
+    /// on to a child process with the same FD number and name.
+    fn forward(&mut self, raw_fd: RawFd) -> Option<NamedFdMapping> {
+        Some(NamedFdMapping {
+            name: self.name.clone(),
+            mapping: FdMapping {
+                parent_fd: self.fd.take()?,
+                child_fd: raw_fd,
+            },
+        })
+    }
+}
+
+/// A registry of inherited file descriptors, from which ownership of each can be taken at most
+/// once.
+///
+/// The process-wide registry used by [`take_fd_ownership`] and friends is set up by
+/// [`init_inherited_fds`], but separate registries may also be created, e.g. by libraries or
+/// tests. Any file descriptors which haven't been taken are closed when the registry is dropped.
+#[derive(Debug, Default)]
+pub struct InheritedFds {
+    entries: BTreeMap<RawFd, Entry>,
+}
+
+impl InheritedFds {
+    /// Creates a new empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new registry and takes ownership of the open file descriptors in this process
+    /// selected by the given options.
+    ///
+    /// # Safety
+    ///
+    /// Ownership of none of the file descriptors selected by `options` may have been taken
+    /// already.
+    pub unsafe fn from_open_fds(options: &InitOptions) -> Result<Self, std::io::Error> {
+        let mut fds = Self::new();
 
-    let fd_path = canonicalize("/proc/self/fd")?;
+        let fd_path = canonicalize(options.proc_root.join("self/fd"))?;
 
-    for entry in read_dir(&fd_path)? {
-        let entry = entry?;
+        // Read the whole directory first, so that nothing is taken if there is an unexpected
+        // entry. Files in /proc/self/fd are always numbers, but `proc_root` may point elsewhere.
+        let entries = read_dir(&fd_path)?
+            .map(|entry| {
+                let entry = entry?;
+                let raw_fd = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.parse::<RawFd>().ok())
+                    .filter(|raw_fd| *raw_fd >= 0)
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Unexpected entry {:?} in {fd_path:?}", entry.file_name()),
+                        )
+                    })?;
+                Ok((raw_fd, entry.path()))
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
 
-        // Files in /prod/self/fd are guaranteed to be numbers. So parsing is always successful.
-        let file_name = entry.file_name();
-        let raw_fd = file_name.to_str().unwrap().parse::<RawFd>().unwrap();
+        for (raw_fd, path) in entries {
+            // By default we don't take ownership of the stdio FDs as the Rust runtime owns them.
+            if !options.should_take(raw_fd) {
+                continue;
+            }
+
+            // Exceptional case: /proc/self/fd/* may be a dir fd created by read_dir just above.
+            // Since the file descriptor is owned by read_dir (and thus closed by it), we shouldn't
+            // take ownership to it.
+            if path.read_link().is_ok_and(|target| target == fd_path) {
+                continue;
+            }
+
+            // SAFETY: /proc/self/fd/* are file descriptors that are open. Our caller guarantees
+            // that this is the first time to claim the ownership of these file descriptors.
+            let owned_fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
 
-        // We don't take ownership of the stdio FDs as the Rust runtime owns them.
-        if [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].contains(&raw_fd) {
-            continue;
+            if options.set_cloexec {
+                fcntl(&owned_fd, F_SETFD(FdFlag::FD_CLOEXEC))?;
+            }
+            fds.insert(owned_fd, None);
         }
 
-        // Exceptional case: /proc/self/fd/* may be a dir fd created by read_dir just above. Since
-        // the file descriptor is owned by read_dir (and thus closed by it), we shouldn't take
-        // ownership to it.
-        if entry.path().read_link()? == fd_path {
-            continue;
+        Ok(fds)
+    }
+
+    /// Adds the given file descriptor to the registry, optionally with a name.
+    ///
+    /// This replaces any previous entry with the same FD number, which must have been taken
+    /// already.
+    pub fn insert(&mut self, fd: OwnedFd, name: Option<String>) {
+        self.entries
+            .insert(fd.as_raw_fd(), Entry { fd: Some(fd), name });
+    }
+
+    /// Sets the name of the given inherited file descriptor.
+    pub fn set_name(&mut self, raw_fd: RawFd, name: String) -> Result<(), InheritedFdError> {
+        let entry = self
+            .entries
+            .get_mut(&raw_fd)
+            .ok_or(InheritedFdError::FileDescriptorNotInherited(raw_fd))?;
+        entry.name = Some(name);
+        Ok(())
+    }
+
+    /// Names file descriptors in the registry according to the [`FD_NAMES_ENV_VAR`] environment
+    /// variable, if it is set.
+    ///
+    /// An error is returned if the variable is malformed or refers to a file descriptor which is
+    /// not in the registry.
+    pub fn set_names_from_env(&mut self) -> Result<(), InheritedFdError> {
+        match std::env::var(FD_NAMES_ENV_VAR) {
+            Ok(value) => self.set_names_from(&value),
+            Err(VarError::NotPresent) => Ok(()),
+            Err(VarError::NotUnicode(_)) => {
+                Err(InheritedFdError::InvalidEnvVar(FD_NAMES_ENV_VAR.to_owned()))
+            }
         }
+    }
 
-        // SAFETY: /proc/self/fd/* are file descriptors that are open. If `init_inherited_fds()` was
-        // called at the very beginning of the program execution (as requested by the safety
-        // requirement of this function), this is the first time to claim the ownership of these
-        // file descriptors.
-        let owned_fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+    fn set_names_from(&mut self, value: &str) -> Result<(), InheritedFdError> {
+        for (raw_fd, name) in parse_fd_names(value)? {
+            self.set_name(raw_fd, name)?;
+        }
+        Ok(())
+    }
 
-        fcntl(&owned_fd, F_SETFD(FdFlag::FD_CLOEXEC))?;
-        fds.insert(raw_fd, Some(owned_fd));
+    /// Names file descriptors in the registry according to the given protocol, and returns the
+    /// file descriptors which the protocol advertised in order.
+    ///
+    /// File descriptors advertised without a name are left unnamed. An error is returned if the
+    /// advertisement is malformed or refers to a file descriptor which is not in the registry.
+    pub fn apply_protocol(
+        &mut self,
+        protocol: &impl FdProtocol,
+    ) -> Result<Vec<RawFd>, InheritedFdError> {
+        self.apply_protocol_with_env(protocol, &|var| std::env::var(var).ok())
     }
 
-    INHERITED_FDS
-        .set(Mutex::new(fds))
-        .or(Err(std::io::Error::other(
-            "Inherited fds were already initialized",
-        )))
+    pub(crate) fn apply_protocol_with_env(
+        &mut self,
+        protocol: &impl FdProtocol,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Vec<RawFd>, InheritedFdError> {
+        let advertised = protocol.parse(env, self)?;
+        for (raw_fd, name) in &advertised {
+            if !self.entries.contains_key(raw_fd) {
+                return Err(InheritedFdError::FileDescriptorNotInherited(*raw_fd));
+            }
+            if let Some(name) = name {
+                self.set_name(*raw_fd, name.clone())?;
+            }
+        }
+        Ok(advertised.into_iter().map(|(raw_fd, _)| raw_fd).collect())
+    }
+
+    /// Takes the control socket passed by
+    /// [`spawn_with_control`](crate::control::spawn_with_control), according to the
+    /// [`CONTROL_FD_ENV_VAR`] environment variable.
+    ///
+    /// File descriptors sent by the parent process can then be received with
+    /// [`recv_fds_into`](crate::transfer::recv_fds_into). Returns `None` if this process wasn't
+    /// passed a control socket by its parent.
+    pub fn take_control_socket(&mut self) -> Result<Option<UnixStream>, InheritedFdError> {
+        let Some(raw_fd) = fd_from_parent_env(CONTROL_FD_ENV_VAR)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.take(raw_fd)?.into()))
+    }
+
+    /// Takes the socket with the given name, according to the `ANDROID_SOCKET_<name>` environment
+    /// variable used by Android init.
+    ///
+    /// Like `android_get_control_socket`, this checks that the file descriptor is a socket before
+    /// taking it.
+    pub fn take_android_socket(&mut self, name: &str) -> Result<OwnedFd, InheritedFdError> {
+        let raw_fd = fd_from_env(&android_socket_env_var(name))?
+            .ok_or_else(|| InheritedFdError::NameNotFound(name.to_owned()))?;
+        self.take_android_socket_at(raw_fd, name)
+    }
+
+    fn take_android_socket_at(
+        &mut self,
+        raw_fd: RawFd,
+        name: &str,
+    ) -> Result<OwnedFd, InheritedFdError> {
+        let fd = self.borrow_untaken(raw_fd)?;
+        let stat = fstat(fd).map_err(|e| InheritedFdError::QueryFailed(raw_fd, e))?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFSOCK {
+            return Err(InheritedFdError::UnexpectedFd(
+                raw_fd,
+                format!("socket {name:?}"),
+            ));
+        }
+        self.take(raw_fd)
+    }
+
+    /// Takes the file with the given path, according to the `ANDROID_FILE_<path>` environment
+    /// variable used by Android init. See [`android_file_env_var`] for how the variable is named.
+    ///
+    /// Like `android_get_control_file`, this checks that the file descriptor refers to the file at
+    /// the given path before taking it.
+    pub fn take_android_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<OwnedFd, InheritedFdError> {
+        let path = path.as_ref();
+        let raw_fd = fd_from_env(&android_file_env_var(path))?
+            .ok_or_else(|| InheritedFdError::NameNotFound(path.display().to_string()))?;
+        self.take_android_file_at(raw_fd, path)
+    }
+
+    fn take_android_file_at(
+        &mut self,
+        raw_fd: RawFd,
+        path: &Path,
+    ) -> Result<OwnedFd, InheritedFdError> {
+        self.borrow_untaken(raw_fd)?;
+        let expected = canonicalize(path).ok();
+        let actual = read_link(format!("/proc/self/fd/{raw_fd}")).ok();
+        if expected.is_none() || expected != actual {
+            return Err(InheritedFdError::UnexpectedFd(
+                raw_fd,
+                format!("file {}", path.display()),
+            ));
+        }
+        self.take(raw_fd)
+    }
+
+    /// Takes the lowest-numbered untaken file descriptor with the given name, after checking that
+    /// it is a directory.
+    ///
+    /// This works for directories opened with `O_PATH` as well as those opened for reading.
+    pub fn take_dir(&mut self, name: &str) -> Result<OwnedFd, InheritedFdError> {
+        let Some(raw_fd) = self
+            .entries
+            .iter()
+            .find(|(_, entry)| entry.name.as_deref() == Some(name) && entry.fd.is_some())
+            .map(|(&raw_fd, _)| raw_fd)
+        else {
+            // Let `take_by_name` work out the appropriate error.
+            return self.take_by_name(name);
+        };
+        let fd = self.borrow_untaken(raw_fd)?;
+        let stat = fstat(fd).map_err(|e| InheritedFdError::QueryFailed(raw_fd, e))?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFDIR {
+            return Err(InheritedFdError::UnexpectedFd(
+                raw_fd,
+                format!("directory {name:?}"),
+            ));
+        }
+        self.take(raw_fd)
+    }
+
+    /// Takes the lowest-numbered untaken file descriptor with the given name as a
+    /// [`cap_std::fs::Dir`], after checking that it is a directory.
+    #[cfg(feature = "cap-std")]
+    pub fn take_cap_std_dir(&mut self, name: &str) -> Result<cap_std::fs::Dir, InheritedFdError> {
+        let fd = self.take_dir(name)?;
+        Ok(cap_std::fs::Dir::from_std_file(fd.into()))
+    }
+
+    /// Takes the GNU make jobserver advertised in the first of the [`MAKEFLAGS_ENV_VARS`]
+    /// environment variables which is set, if any.
+    ///
+    /// For a pipe jobserver both ends of the pipe are taken from the registry. For a FIFO
+    /// jobserver, the FIFO is opened by its path. Returns `None` if no jobserver is advertised.
+    pub fn take_jobserver(&mut self) -> Result<Option<Jobserver>, InheritedFdError> {
+        let Some((var, makeflags)) = MAKEFLAGS_ENV_VARS
+            .iter()
+            .find_map(|&var| Some((var, std::env::var(var).ok()?)))
+        else {
+            return Ok(None);
+        };
+        self.take_jobserver_from(var, &makeflags)
+    }
+
+    fn take_jobserver_from(
+        &mut self,
+        var: &str,
+        makeflags: &str,
+    ) -> Result<Option<Jobserver>, InheritedFdError> {
+        match parse_makeflags(makeflags) {
+            None => Ok(None),
+            Some(Err(())) => Err(InheritedFdError::InvalidEnvVar(var.to_owned())),
+            Some(Ok(JobserverAuth::Fds(read_fd, write_fd))) => {
+                // Taking the same FD twice would close it when the second take failed.
+                if read_fd == write_fd {
+                    return Err(InheritedFdError::InvalidEnvVar(var.to_owned()));
+                }
+                self.borrow_untaken(read_fd)?;
+                self.borrow_untaken(write_fd)?;
+                Ok(Some(Jobserver::from_pipe(
+                    self.take(read_fd)?,
+                    self.take(write_fd)?,
+                )))
+            }
+            Some(Ok(JobserverAuth::Fifo(path))) => Jobserver::open_fifo(&path)
+                .map(Some)
+                .map_err(|e| InheritedFdError::OpenFailed(path, e)),
+        }
+    }
+
+    /// Borrows the given file descriptor from the registry, returning the same errors as
+    /// [`take`](Self::take) if it can't be taken.
+    fn borrow_untaken(&self, raw_fd: RawFd) -> Result<BorrowedFd<'_>, InheritedFdError> {
+        self.entries
+            .get(&raw_fd)
+            .ok_or(InheritedFdError::FileDescriptorNotInherited(raw_fd))?
+            .fd
+            .as_ref()
+            .map(AsFd::as_fd)
+            .ok_or(InheritedFdError::OwnershipTaken(raw_fd))
+    }
+
+    /// Returns the name of the given inherited file descriptor, if it has one.
+    pub fn name(&self, raw_fd: RawFd) -> Option<&str> {
+        self.entries.get(&raw_fd)?.name.as_deref()
+    }
+
+    /// Returns the names of all named file descriptors in the registry, sorted by FD number, along
+    /// with the FD numbers.
+    pub fn names(&self) -> impl Iterator<Item = (&str, RawFd)> {
+        self.entries
+            .iter()
+            .filter_map(|(&raw_fd, entry)| Some((entry.name.as_deref()?, raw_fd)))
+    }
+
+    /// Iterates over all file descriptors in the registry, sorted by FD number.
+    ///
+    /// The file descriptor is borrowed for entries which haven't yet been taken, or `None` for
+    /// those which have.
+    pub fn iter(&self) -> impl Iterator<Item = (RawFd, Option<BorrowedFd<'_>>)> {
+        self.entries
+            .iter()
+            .map(|(&raw_fd, entry)| (raw_fd, entry.fd.as_ref().map(AsFd::as_fd)))
+    }
+
+    /// Takes the ownership of the given `RawFd` and returns an `OwnedFd` for it.
+    ///
+    /// An error is returned when the ownership was already taken (by a prior call to this
+    /// function with the same `RawFd`) or `RawFd` is not in the registry.
+    pub fn take(&mut self, raw_fd: RawFd) -> Result<OwnedFd, InheritedFdError> {
+        let entry = self
+            .entries
+            .get_mut(&raw_fd)
+            .ok_or(InheritedFdError::FileDescriptorNotInherited(raw_fd))?;
+        entry
+            .fd
+            .take()
+            .ok_or(InheritedFdError::OwnershipTaken(raw_fd))
+    }
+
+    /// Takes the ownership of the lowest-numbered file descriptor with the given name which hasn't
+    /// already been taken.
+    ///
+    /// An error is returned if there are no file descriptors with the given name, or if ownership
+    /// of all of them has already been taken.
+    pub fn take_by_name(&mut self, name: &str) -> Result<OwnedFd, InheritedFdError> {
+        let mut named = self
+            .entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.name.as_deref() == Some(name))
+            .peekable();
+        let &(&first_fd, _) = named
+            .peek()
+            .ok_or_else(|| InheritedFdError::NameNotFound(name.to_owned()))?;
+        named
+            .find_map(|(_, entry)| entry.fd.take())
+            .ok_or(InheritedFdError::OwnershipTaken(first_fd))
+    }
+
+    /// Takes ownership of all file descriptors in the registry which haven't yet been claimed,
+    /// sorted by FD number.
+    pub fn take_unclaimed(&mut self) -> Vec<OwnedFd> {
+        self.entries
+            .values_mut()
+            .filter_map(|entry| entry.fd.take())
+            .collect()
+    }
+
+    /// Closes all file descriptors in the registry which haven't yet been claimed, and returns the
+    /// FD numbers which were closed.
+    pub fn close_unclaimed(&mut self) -> Vec<RawFd> {
+        self.take_unclaimed()
+            .into_iter()
+            .map(|owned_fd| owned_fd.as_raw_fd())
+            .collect()
+    }
+
+    /// Closes all file descriptors in the registry which haven't yet been claimed, and returns an
+    /// error listing them if there were any.
+    pub fn ensure_all_claimed(&mut self) -> Result<(), InheritedFdError> {
+        let unclaimed = self.close_unclaimed();
+        if unclaimed.is_empty() {
+            Ok(())
+        } else {
+            Err(InheritedFdError::UnclaimedFds(unclaimed))
+        }
+    }
+
+    /// Takes ownership of the given file descriptors and returns mappings to pass them on to a
+    /// child process, with the same FD numbers and names as they have in the registry.
+    ///
+    /// An error is returned if any of the file descriptors is not in the registry, was already
+    /// taken or is given more than once, in which case none of them are taken.
+    pub fn forward(&mut self, raw_fds: &[RawFd]) -> Result<Vec<NamedFdMapping>, InheritedFdError> {
+        for (i, &raw_fd) in raw_fds.iter().enumerate() {
+            let entry = self
+                .entries
+                .get(&raw_fd)
+                .ok_or(InheritedFdError::FileDescriptorNotInherited(raw_fd))?;
+            if entry.fd.is_none() || raw_fds[..i].contains(&raw_fd) {
+                return Err(InheritedFdError::OwnershipTaken(raw_fd));
+            }
+        }
+        Ok(raw_fds
+            .iter()
+            .filter_map(|&raw_fd| self.entries.get_mut(&raw_fd)?.forward(raw_fd))
+            .collect())
+    }
+
+    /// Takes ownership of all file descriptors with the given name which haven't already been
+    /// taken, and returns mappings to pass them on to a child process with the same FD numbers and
+    /// names.
+    pub fn forward_by_name(&mut self, name: &str) -> Vec<NamedFdMapping> {
+        self.entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.name.as_deref() == Some(name))
+            .filter_map(|(&raw_fd, entry)| entry.forward(raw_fd))
+            .collect()
+    }
+
+    /// Takes ownership of all file descriptors in the registry which haven't yet been claimed, and
+    /// returns mappings to pass them on to a child process with the same FD numbers and names.
+    pub fn forward_unclaimed(&mut self) -> Vec<NamedFdMapping> {
+        self.entries
+            .iter_mut()
+            .filter_map(|(&raw_fd, entry)| entry.forward(raw_fd))
+            .collect()
+    }
+
+    /// Returns information about all file descriptors in the registry, sorted by FD number.
+    pub fn info(&self) -> Result<Vec<InheritedFdInfo>, InheritedFdError> {
+        self.entries
+            .iter()
+            .map(|(&raw_fd, entry)| {
+                let metadata = entry
+                    .fd
+                    .as_ref()
+                    .map(|owned_fd| fd_metadata(owned_fd.as_fd()))
+                    .transpose()
+                    .map_err(|e| InheritedFdError::QueryFailed(raw_fd, e))?;
+                Ok(InheritedFdInfo {
+                    fd: raw_fd,
+                    name: entry.name.clone(),
+                    taken: entry.fd.is_none(),
+                    metadata,
+                })
+            })
+            .collect()
+    }
 }
 
-/// Takes the ownership of the given `RawFd` and returns an `OwnedFd` for it.
-///
-/// The returned FD will have the `FD_CLOEXEC` flag set.
+/// Returns whether the given name can be advertised in the [`FD_NAMES_ENV_VAR`] environment
+/// variable, i.e. it isn't empty and doesn't contain `:`.
+pub(crate) fn is_valid_fd_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(':')
+}
+
+/// Formats the names of the given mappings as expected in the [`FD_NAMES_ENV_VAR`] environment
+/// variable. Mappings without names are skipped.
 ///
-/// An error is returned when the ownership was already taken (by a prior call to this
-/// function with the same `RawFd`) or `RawFd` is not an inherited file descriptor.
-pub fn take_fd_ownership(raw_fd: RawFd) -> Result<OwnedFd, InheritedFdError> {
-    let mut fds = INHERITED_FDS
-        .get()
-        .ok_or(InheritedFdError::NotInitialized)?
-        .lock()
-        .unwrap();
-
-    if let Some(value) = fds.get_mut(&raw_fd) {
-        if let Some(owned_fd) = value.take() {
-            Ok(owned_fd)
-        } else {
-            Err(InheritedFdError::OwnershipTaken(raw_fd))
+/// Names must be valid according to [`is_valid_fd_name`].
+pub(crate) fn format_fd_names(mappings: &[NamedFdMapping]) -> String {
+    mappings
+        .iter()
+        .filter_map(|named| {
+            Some(format!(
+                "{}={}",
+                named.mapping.child_fd,
+                named.name.as_ref()?
+            ))
+        })
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Gathers metadata about the given open file descriptor.
+fn fd_metadata(fd: BorrowedFd) -> Result<FdMetadata, Errno> {
+    let stat = fstat(fd)?;
+    let file_type = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+        SFlag::S_IFREG => FdFileType::RegularFile,
+        SFlag::S_IFDIR => FdFileType::Directory,
+        SFlag::S_IFLNK => FdFileType::Symlink,
+        SFlag::S_IFCHR => FdFileType::CharacterDevice,
+        SFlag::S_IFBLK => FdFileType::BlockDevice,
+        SFlag::S_IFIFO => FdFileType::Fifo,
+        SFlag::S_IFSOCK => FdFileType::Socket,
+        _ => FdFileType::Unknown,
+    };
+
+    let flags = OFlag::from_bits_truncate(fcntl(fd, F_GETFL)?);
+    let access_mode = if flags.contains(OFlag::O_PATH) {
+        FdAccessMode::Path
+    } else {
+        match flags & OFlag::O_ACCMODE {
+            OFlag::O_WRONLY => FdAccessMode::WriteOnly,
+            OFlag::O_RDWR => FdAccessMode::ReadWrite,
+            _ => FdAccessMode::ReadOnly,
         }
+    };
+
+    // O_PATH sockets can't be queried with getsockname.
+    let socket_address = if file_type == FdFileType::Socket && access_mode != FdAccessMode::Path {
+        Some(getsockname::<SockaddrStorage>(fd.as_raw_fd())?.to_string())
     } else {
-        Err(InheritedFdError::FileDescriptorNotInherited(raw_fd))
-    }
+        None
+    };
+
+    let path = read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok();
+    let position = read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))
+        .ok()
+        .and_then(|fdinfo| parse_fdinfo_position(&fdinfo));
+
+    Ok(FdMetadata {
+        file_type,
+        path,
+        socket_address,
+        access_mode,
+        nonblocking: flags.contains(OFlag::O_NONBLOCK),
+        append: flags.contains(OFlag::O_APPEND),
+        position,
+    })
+}
+
+/// Parses the `pos` field out of the contents of a `/proc/self/fdinfo/*` file.
+fn parse_fdinfo_position(fdinfo: &str) -> Option<u64> {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("pos:"))
+        .and_then(|pos| pos.trim().parse().ok())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{CommandFdExt, tests::setup};
     use nix::unistd::close;
+    use std::fs::{File, create_dir_all};
     use std::{
-        io,
+        io::{self, Write},
+        net::TcpListener,
         os::fd::{AsRawFd, IntoRawFd},
+        process::Command,
     };
-    use tempfile::tempfile;
+    use tempfile::{tempdir, tempfile};
 
     struct Fixture {
         fds: Vec<RawFd>,
@@ -134,6 +1083,7 @@ mod test {
 
     impl Fixture {
         fn setup(num_fds: usize) -> Result<Self, io::Error> {
+            setup();
             let mut fds = Vec::new();
             for _ in 0..num_fds {
                 fds.push(tempfile()?.into_raw_fd());
@@ -146,6 +1096,24 @@ mod test {
             self.fds.push(raw_fd);
             Ok(raw_fd)
         }
+
+        /// Returns options to take only the FDs opened by the fixture.
+        fn options(&self) -> InitOptions {
+            InitOptions {
+                include: self.fds.iter().map(|&fd| fd..=fd).collect(),
+                ..Default::default()
+            }
+        }
+
+        /// Takes ownership of the FDs opened by the fixture in a new registry, as if they were
+        /// inherited.
+        fn inherit(&mut self) -> InheritedFds {
+            // SAFETY: The fixture gives up ownership of its FDs here, so they won't be closed
+            // twice.
+            let fds = unsafe { InheritedFds::from_open_fds(&self.options()).unwrap() };
+            self.fds.clear();
+            fds
+        }
     }
 
     impl Drop for Fixture {
@@ -164,17 +1132,13 @@ mod test {
 
     #[test]
     fn happy_case() {
-        let fixture = Fixture::setup(2).unwrap();
+        let mut fixture = Fixture::setup(2).unwrap();
         let f0 = fixture.fds[0];
         let f1 = fixture.fds[1];
+        let mut fds = fixture.inherit();
 
-        // SAFETY: assume files opened by Fixture are inherited ones
-        unsafe {
-            init_inherited_fds().unwrap();
-        }
-
-        let f0_owned = take_fd_ownership(f0).unwrap();
-        let f1_owned = take_fd_ownership(f1).unwrap();
+        let f0_owned = fds.take(f0).unwrap();
+        let f1_owned = fds.take(f1).unwrap();
         assert_eq!(f0, f0_owned.as_raw_fd());
         assert_eq!(f1, f1_owned.as_raw_fd());
 
@@ -187,80 +1151,81 @@ mod test {
     #[test]
     fn access_non_inherited_fd() {
         let mut fixture = Fixture::setup(2).unwrap();
-
-        // SAFETY: assume files opened by Fixture are inherited ones
-        unsafe {
-            init_inherited_fds().unwrap();
-        }
+        let mut fds = fixture.inherit();
 
         let f = fixture.open_new_file().unwrap();
         assert_eq!(
-            take_fd_ownership(f).err(),
+            fds.take(f).err(),
             Some(InheritedFdError::FileDescriptorNotInherited(f))
         );
     }
 
     #[test]
-    fn call_init_inherited_fds_multiple_times() {
-        let _ = Fixture::setup(2).unwrap();
+    fn global_registry() {
+        // This is the only test which uses the process-wide registry, as it can only be
+        // initialised once.
+        let fixture = Fixture::setup(2).unwrap();
+        let f = fixture.fds[0];
+
+        assert_eq!(
+            take_fd_ownership(f).err(),
+            Some(InheritedFdError::NotInitialized)
+        );
 
         // SAFETY: assume files opened by Fixture are inherited ones
         unsafe {
-            init_inherited_fds().unwrap();
+            init_inherited_fds_with_options(&fixture.options()).unwrap();
         }
+        // The registry now owns the FDs, so the fixture mustn't close them.
+        let f_other = fixture.fds[1];
+        std::mem::forget(fixture);
 
         // SAFETY: for testing
         let res = unsafe { init_inherited_fds() };
         assert!(res.is_err());
-    }
 
-    #[test]
-    fn access_without_init_inherited_fds() {
-        let fixture = Fixture::setup(2).unwrap();
-
-        let f = fixture.fds[0];
+        let f_owned = take_fd_ownership(f).unwrap();
+        assert_eq!(f_owned.as_raw_fd(), f);
         assert_eq!(
             take_fd_ownership(f).err(),
-            Some(InheritedFdError::NotInitialized)
+            Some(InheritedFdError::OwnershipTaken(f))
+        );
+        assert_eq!(
+            ensure_all_fds_claimed(),
+            Err(InheritedFdError::UnclaimedFds(vec![f_other]))
         );
+        assert!(!is_fd_opened(f_other));
+        assert_eq!(ensure_all_fds_claimed(), Ok(()));
     }
 
     #[test]
     fn double_ownership() {
-        let fixture = Fixture::setup(2).unwrap();
+        let mut fixture = Fixture::setup(2).unwrap();
         let f = fixture.fds[0];
+        let mut fds = fixture.inherit();
 
-        // SAFETY: assume files opened by Fixture are inherited ones
-        unsafe {
-            init_inherited_fds().unwrap();
-        }
-
-        let f_owned = take_fd_ownership(f).unwrap();
-        let f_double_owned = take_fd_ownership(f);
+        let f_owned = fds.take(f).unwrap();
+        let f_double_owned = fds.take(f);
         assert_eq!(
             f_double_owned.err(),
             Some(InheritedFdError::OwnershipTaken(f)),
         );
 
-        // just to highlight that f_owned is kept alive when the second call to take_fd_ownership
+        // just to highlight that f_owned is kept alive when the second call to take
         // is made.
         drop(f_owned);
     }
 
     #[test]
     fn take_drop_retake() {
-        let fixture = Fixture::setup(2).unwrap();
+        let mut fixture = Fixture::setup(2).unwrap();
         let f = fixture.fds[0];
+        let mut fds = fixture.inherit();
 
-        // SAFETY: assume files opened by Fixture are inherited ones
-        unsafe {
-            init_inherited_fds().unwrap();
-        }
-
-        let f_owned = take_fd_ownership(f).unwrap();
+        let f_owned = fds.take(f).unwrap();
         drop(f_owned);
 
-        let f_double_owned = take_fd_ownership(f);
+        let f_double_owned = fds.take(f);
         assert_eq!(
             f_double_owned.err(),
             Some(InheritedFdError::OwnershipTaken(f)),
@@ -269,22 +1234,496 @@ mod test {
 
     #[test]
     fn cloexec() {
-        let fixture = Fixture::setup(2).unwrap();
+        let mut fixture = Fixture::setup(2).unwrap();
         let f = fixture.fds[0];
 
         // SAFETY: 0 is a valid parameter for F_SETFD.
         let res = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_SETFD, 0) };
         assert_ne!(res, -1);
 
-        // SAFETY: assume files opened by Fixture are inherited ones
-        unsafe {
-            init_inherited_fds().unwrap();
-        }
+        let _fds = fixture.inherit();
 
         // SAFETY: F_GETFD doesn't need any extra parameters.
         let flags = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFD) };
         assert_ne!(flags, -1);
-        // FD_CLOEXEC should be set by init_inherited_fds
+        // FD_CLOEXEC should be set when the FDs are taken.
         assert_eq!(flags, FdFlag::FD_CLOEXEC.bits());
     }
+
+    #[test]
+    fn unclaimed_closed_on_drop() {
+        let mut fixture = Fixture::setup(2).unwrap();
+        let f0 = fixture.fds[0];
+        let f1 = fixture.fds[1];
+        let mut fds = fixture.inherit();
+
+        let f0_owned = fds.take(f0).unwrap();
+        drop(fds);
+
+        assert!(is_fd_opened(f0_owned.as_raw_fd()));
+        assert!(!is_fd_opened(f1));
+    }
+
+    #[test]
+    fn take_by_name() {
+        let mut fixture = Fixture::setup(3).unwrap();
+        let f0 = fixture.fds[0];
+        let f1 = fixture.fds[1];
+        let f2 = fixture.fds[2];
+        let mut fds = fixture.inherit();
+
+        fds.set_name(f0, "a".to_owned()).unwrap();
+        fds.set_name(f2, "a".to_owned()).unwrap();
+        fds.set_name(f1, "b".to_owned()).unwrap();
+        assert_eq!(
+            fds.set_name(-1, "c".to_owned()),
+            Err(InheritedFdError::FileDescriptorNotInherited(-1))
+        );
+        assert_eq!(fds.name(f1), Some("b"));
+        assert_eq!(
+            fds.names().collect::<Vec<_>>(),
+            vec![("a", f0), ("b", f1), ("a", f2)]
+        );
+
+        assert_eq!(fds.take_by_name("a").unwrap().as_raw_fd(), f0);
+        assert_eq!(fds.take_by_name("a").unwrap().as_raw_fd(), f2);
+        assert_eq!(
+            fds.take_by_name("a").err(),
+            Some(InheritedFdError::OwnershipTaken(f0))
+        );
+        assert_eq!(
+            fds.take_by_name("c").err(),
+            Some(InheritedFdError::NameNotFound("c".to_owned()))
+        );
+        assert_eq!(
+            fds.iter()
+                .map(|(raw_fd, fd)| (raw_fd, fd.is_some()))
+                .collect::<Vec<_>>(),
+            vec![(f0, false), (f1, true), (f2, false)]
+        );
+    }
+
+    #[test]
+    fn insert() {
+        setup();
+        let mut fds = InheritedFds::new();
+        let file: OwnedFd = tempfile().unwrap().into();
+        let raw_fd = file.as_raw_fd();
+
+        fds.insert(file, Some("file".to_owned()));
+
+        let info = fds.info().unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].fd, raw_fd);
+        assert_eq!(info[0].name.as_deref(), Some("file"));
+        assert!(!info[0].taken);
+        assert_eq!(
+            info[0].metadata.as_ref().unwrap().file_type,
+            FdFileType::RegularFile
+        );
+        assert_eq!(fds.take_by_name("file").unwrap().as_raw_fd(), raw_fd);
+    }
+
+    #[test]
+    fn metadata_of_file() {
+        setup();
+        let mut file = tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+
+        let metadata = fd_metadata(file.as_fd()).unwrap();
+        assert_eq!(metadata.file_type, FdFileType::RegularFile);
+        assert_eq!(metadata.access_mode, FdAccessMode::ReadWrite);
+        assert_eq!(metadata.socket_address, None);
+        assert!(!metadata.nonblocking);
+        assert!(!metadata.append);
+        assert_eq!(metadata.position, Some(5));
+    }
+
+    #[test]
+    fn metadata_of_socket() {
+        setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let metadata = fd_metadata(listener.as_fd()).unwrap();
+        assert_eq!(metadata.file_type, FdFileType::Socket);
+        assert_eq!(
+            metadata.socket_address,
+            Some(listener.local_addr().unwrap().to_string())
+        );
+        assert!(metadata.nonblocking);
+    }
+
+    #[test]
+    fn take_only_unclaimed() {
+        let mut fixture = Fixture::setup(3).unwrap();
+        let raw_fds = fixture.fds.clone();
+        let mut fds = fixture.inherit();
+        let claimed = fds.take(raw_fds[1]).unwrap();
+
+        let unclaimed = fds.take_unclaimed();
+        assert_eq!(
+            unclaimed.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>(),
+            vec![raw_fds[0], raw_fds[2]]
+        );
+        assert!(fds.iter().all(|(_, fd)| fd.is_none()));
+        assert!(fds.take_unclaimed().is_empty());
+
+        drop(unclaimed);
+        assert!(!is_fd_opened(raw_fds[0]));
+        assert!(is_fd_opened(claimed.as_raw_fd()));
+        assert!(!is_fd_opened(raw_fds[2]));
+    }
+
+    #[test]
+    fn options_select_fds() {
+        let fixture = Fixture::setup(4).unwrap();
+
+        // Fake a proc filesystem listing all the fixture's FDs as well as stdin.
+        let proc_root = tempdir().unwrap();
+        let fd_dir = proc_root.path().join("self/fd");
+        create_dir_all(&fd_dir).unwrap();
+        for raw_fd in [0].iter().chain(&fixture.fds) {
+            File::create(fd_dir.join(raw_fd.to_string())).unwrap();
+        }
+
+        // SAFETY: 0 is a valid parameter for F_SETFD.
+        let res = unsafe { libc::fcntl(fixture.fds[0], libc::F_SETFD, 0) };
+        assert_ne!(res, -1);
+
+        let options = InitOptions {
+            exclude: vec![fixture.fds[1]..=fixture.fds[2]],
+            set_cloexec: false,
+            proc_root: proc_root.path().to_owned(),
+            ..Default::default()
+        };
+        // SAFETY: The FDs are owned by the fixture, and are released again below.
+        let mut fds = unsafe { InheritedFds::from_open_fds(&options).unwrap() };
+
+        assert_eq!(
+            fds.iter().map(|(raw_fd, _)| raw_fd).collect::<Vec<_>>(),
+            vec![fixture.fds[0], fixture.fds[3]]
+        );
+
+        // FD_CLOEXEC should have been left alone.
+        // SAFETY: F_GETFD doesn't need any extra parameters.
+        let flags = unsafe { libc::fcntl(fixture.fds[0], libc::F_GETFD) };
+        assert_eq!(flags, 0);
+
+        // Don't close the FDs here, as the fixture will.
+        for owned_fd in fds.take_unclaimed() {
+            let _ = owned_fd.into_raw_fd();
+        }
+    }
+
+    #[test]
+    fn options_invalid_proc_entry() {
+        let fixture = Fixture::setup(1).unwrap();
+
+        let proc_root = tempdir().unwrap();
+        let fd_dir = proc_root.path().join("self/fd");
+        create_dir_all(&fd_dir).unwrap();
+        File::create(fd_dir.join(fixture.fds[0].to_string())).unwrap();
+        File::create(fd_dir.join("not-a-number")).unwrap();
+
+        let options = InitOptions {
+            proc_root: proc_root.path().to_owned(),
+            ..Default::default()
+        };
+        // SAFETY: Nothing is taken, as the listing is rejected before any FDs are taken.
+        let error = unsafe { InheritedFds::from_open_fds(&options) }.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(is_fd_opened(fixture.fds[0]));
+    }
+
+    #[test]
+    fn options_include_stdio() {
+        let options = InitOptions {
+            include: vec![0..=0, 3..=9],
+            include_stdio: true,
+            ..Default::default()
+        };
+        assert!(options.should_take(0));
+        assert!(!options.should_take(1));
+        assert!(options.should_take(3));
+        assert!(!options.should_take(10));
+        assert!(!InitOptions::default().should_take(0));
+        assert!(InitOptions::default().should_take(3));
+    }
+
+    #[test]
+    fn forward() {
+        let mut fixture = Fixture::setup(3).unwrap();
+        let raw_fds = fixture.fds.clone();
+        let mut fds = fixture.inherit();
+        fds.set_name(raw_fds[1], "b".to_owned()).unwrap();
+        let _claimed = fds.take(raw_fds[2]).unwrap();
+
+        assert_eq!(
+            fds.forward(&[raw_fds[0], raw_fds[2]]).err(),
+            Some(InheritedFdError::OwnershipTaken(raw_fds[2]))
+        );
+        assert_eq!(
+            fds.forward(&[raw_fds[0], raw_fds[0]]).err(),
+            Some(InheritedFdError::OwnershipTaken(raw_fds[0]))
+        );
+        // Nothing should have been taken by the failed calls.
+        let forwarded = fds.forward_unclaimed();
+        assert_eq!(
+            forwarded
+                .iter()
+                .map(|named| (
+                    named.name.as_deref(),
+                    named.mapping.parent_fd.as_raw_fd(),
+                    named.mapping.child_fd
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (None, raw_fds[0], raw_fds[0]),
+                (Some("b"), raw_fds[1], raw_fds[1])
+            ]
+        );
+        assert!(fds.forward_unclaimed().is_empty());
+        assert!(fds.forward_by_name("b").is_empty());
+    }
+
+    #[test]
+    fn forward_to_child() {
+        let mut fixture = Fixture::setup(1).unwrap();
+        let raw_fd = fixture.fds[0];
+        let mut fds = fixture.inherit();
+
+        let mut command = Command::new("ls");
+        command.arg("/proc/self/fd");
+        command
+            .fd_mappings(
+                fds.forward(&[raw_fd])
+                    .unwrap()
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            )
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert!(
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .any(|line| line == raw_fd.to_string())
+        );
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut fixture = Fixture::setup(2).unwrap();
+        let raw_fds = fixture.fds.clone();
+        let mut fds = fixture.inherit();
+        fds.set_name(raw_fds[1], "b".to_owned()).unwrap();
+
+        let names = format_fd_names(&fds.forward_unclaimed());
+        assert_eq!(names, format!("{}=b", raw_fds[1]));
+
+        let mut fixture = Fixture::setup(2).unwrap();
+        let raw_fds = fixture.fds.clone();
+        let mut fds = fixture.inherit();
+        fds.set_names_from(&format!("{}=a=b:{}=c", raw_fds[0], raw_fds[1]))
+            .unwrap();
+        assert_eq!(
+            fds.names().collect::<Vec<_>>(),
+            vec![("a=b", raw_fds[0]), ("c", raw_fds[1])]
+        );
+        assert_eq!(
+            fds.set_names_from("x=a"),
+            Err(InheritedFdError::InvalidEnvVar(FD_NAMES_ENV_VAR.to_owned()))
+        );
+        assert_eq!(
+            fds.set_names_from("-1=a"),
+            Err(InheritedFdError::FileDescriptorNotInherited(-1))
+        );
+    }
+
+    #[test]
+    fn fd_from_parent() {
+        let parent = Pid::from_raw(42);
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(fd_from_parent_env_with("FD", &env(&[]), parent), Ok(None));
+        assert_eq!(
+            fd_from_parent_env_with("FD", &env(&[("FD", "5")]), parent),
+            Ok(Some(5))
+        );
+        assert_eq!(
+            fd_from_parent_env_with("FD", &env(&[("FD", "5"), ("FD_PARENT_PID", "42")]), parent),
+            Ok(Some(5))
+        );
+        // Inherited from further up the process tree, so not meant for this process.
+        assert_eq!(
+            fd_from_parent_env_with("FD", &env(&[("FD", "5"), ("FD_PARENT_PID", "41")]), parent),
+            Ok(None)
+        );
+        assert_eq!(
+            fd_from_parent_env_with("FD", &env(&[("FD", "5"), ("FD_PARENT_PID", "x")]), parent),
+            Err(InheritedFdError::InvalidEnvVar("FD_PARENT_PID".to_owned()))
+        );
+        assert_eq!(
+            fd_from_parent_env_with("FD", &env(&[("FD", "x")]), parent),
+            Err(InheritedFdError::InvalidEnvVar("FD".to_owned()))
+        );
+    }
+
+    #[test]
+    fn android_env_vars() {
+        assert_eq!(android_socket_env_var("zygote"), "ANDROID_SOCKET_zygote");
+        assert_eq!(android_file_env_var("/dev/kmsg"), "ANDROID_FILE__dev_kmsg");
+        assert_eq!(
+            android_file_env_var("/data/my-app.log"),
+            "ANDROID_FILE__data_my_app_log"
+        );
+    }
+
+    #[test]
+    fn take_android_socket_and_file() {
+        setup();
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("file");
+        let file = File::create(&file_path).unwrap();
+        let file_fd = file.as_raw_fd();
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_fd = socket.as_raw_fd();
+        let mut fds = InheritedFds::new();
+        fds.insert(file.into(), None);
+        fds.insert(socket.into(), None);
+
+        assert_eq!(
+            fds.take_android_socket_at(file_fd, "app").unwrap_err(),
+            InheritedFdError::UnexpectedFd(file_fd, "socket \"app\"".to_owned())
+        );
+        assert!(matches!(
+            fds.take_android_file_at(file_fd, &dir.path().join("other")),
+            Err(InheritedFdError::UnexpectedFd(..))
+        ));
+        assert!(matches!(
+            fds.take_android_file_at(socket_fd, &file_path),
+            Err(InheritedFdError::UnexpectedFd(..))
+        ));
+
+        assert_eq!(
+            fds.take_android_socket_at(socket_fd, "app")
+                .unwrap()
+                .as_raw_fd(),
+            socket_fd
+        );
+        assert_eq!(
+            fds.take_android_socket_at(socket_fd, "app").unwrap_err(),
+            InheritedFdError::OwnershipTaken(socket_fd)
+        );
+        assert_eq!(
+            fds.take_android_file_at(file_fd, &file_path)
+                .unwrap()
+                .as_raw_fd(),
+            file_fd
+        );
+    }
+
+    #[test]
+    fn take_dir() {
+        setup();
+        let dir = tempdir().unwrap();
+        let file = File::create(dir.path().join("file")).unwrap();
+        let file_fd = file.as_raw_fd();
+        let mut fds = InheritedFds::new();
+        fds.insert(file.into(), Some("file".to_owned()));
+        fds.insert(
+            crate::dir::open_dir(dir.path()).unwrap(),
+            Some("dir".to_owned()),
+        );
+        fds.insert(
+            crate::dir::open_dir_path(dir.path()).unwrap(),
+            Some("path".to_owned()),
+        );
+
+        assert_eq!(
+            fds.take_dir("file").unwrap_err(),
+            InheritedFdError::UnexpectedFd(file_fd, "directory \"file\"".to_owned())
+        );
+        assert_eq!(
+            fds.take_dir("other").unwrap_err(),
+            InheritedFdError::NameNotFound("other".to_owned())
+        );
+        fds.take_dir("dir").unwrap();
+        assert!(matches!(
+            fds.take_dir("dir"),
+            Err(InheritedFdError::OwnershipTaken(_))
+        ));
+        fds.take_dir("path").unwrap();
+    }
+
+    #[cfg(feature = "cap-std")]
+    #[test]
+    fn take_cap_std_dir() {
+        setup();
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "contents").unwrap();
+        let mut fds = InheritedFds::new();
+        fds.insert(
+            crate::dir::open_dir(dir.path()).unwrap(),
+            Some("dir".to_owned()),
+        );
+        fds.insert(
+            crate::dir::open_dir_path(dir.path()).unwrap(),
+            Some("path".to_owned()),
+        );
+
+        let cap_dir = fds.take_cap_std_dir("dir").unwrap();
+        assert_eq!(cap_dir.read_to_string("file").unwrap(), "contents");
+        let cap_dir = fds.take_cap_std_dir("path").unwrap();
+        assert_eq!(cap_dir.read_to_string("file").unwrap(), "contents");
+        assert!(cap_dir.open("../file").is_err());
+    }
+
+    #[test]
+    fn take_jobserver() {
+        let mut fixture = Fixture::setup(2).unwrap();
+        let raw_fds = fixture.fds.clone();
+        let mut fds = fixture.inherit();
+
+        assert!(
+            fds.take_jobserver_from("MAKEFLAGS", "-j4")
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            fds.take_jobserver_from("MAKEFLAGS", "--jobserver-auth=x")
+                .unwrap_err(),
+            InheritedFdError::InvalidEnvVar("MAKEFLAGS".to_owned())
+        );
+        assert_eq!(
+            fds.take_jobserver_from("MAKEFLAGS", &format!("--jobserver-auth={},-1", raw_fds[0]))
+                .unwrap_err(),
+            InheritedFdError::FileDescriptorNotInherited(-1)
+        );
+        assert_eq!(
+            fds.take_jobserver_from(
+                "MAKEFLAGS",
+                &format!("--jobserver-auth={0},{0}", raw_fds[0])
+            )
+            .unwrap_err(),
+            InheritedFdError::InvalidEnvVar("MAKEFLAGS".to_owned())
+        );
+        let makeflags = format!("-j --jobserver-auth={},{}", raw_fds[0], raw_fds[1]);
+        let jobserver = fds.take_jobserver_from("MAKEFLAGS", &makeflags).unwrap();
+        assert!(jobserver.is_some());
+        assert_eq!(
+            fds.take(raw_fds[0]).unwrap_err(),
+            InheritedFdError::OwnershipTaken(raw_fds[0])
+        );
+    }
 }
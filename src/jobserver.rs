@@ -0,0 +1,388 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A GNU make compatible jobserver, to share a limit on the number of parallel jobs between child
+//! processes such as `make`, `cargo` and `ninja`.
+//!
+//! The parent creates a [`Jobserver`] and passes it to each child with
+//! [`Jobserver::configure`]. A child process can take the jobserver it was passed with
+//! [`take_jobserver`](crate::inherited::take_jobserver), to acquire tokens itself or pass it on
+//! to its own children.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::jobserver::Jobserver;
+//! use std::process::Command;
+//!
+//! let jobserver = Jobserver::new(8).unwrap();
+//! let mut command = Command::new("make");
+//! jobserver.configure(&mut command, 3, 4).unwrap();
+//! command.spawn().unwrap();
+//! ```
+
+use crate::{FdMapping, protocol::Advertisement};
+use nix::{
+    errno::Errno,
+    fcntl::{OFlag, open},
+    sys::stat::Mode,
+    unistd::{mkfifo, pipe2, read, write},
+};
+use std::{
+    fs::remove_file,
+    io,
+    os::fd::{AsFd, BorrowedFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The environment variables in which make and cargo look for jobserver flags, in the order in
+/// which they are checked.
+pub const MAKEFLAGS_ENV_VARS: [&str; 3] = ["CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS"];
+
+/// The byte used for tokens written to a new jobserver.
+const TOKEN: u8 = b'+';
+
+/// How the jobserver is shared.
+#[derive(Debug)]
+enum Channel {
+    /// An anonymous pipe, whose ends are passed to children.
+    Pipe { read: OwnedFd, write: OwnedFd },
+    /// A named FIFO, whose path is passed to children. `fd` is opened for reading and writing.
+    Fifo {
+        path: PathBuf,
+        fd: OwnedFd,
+        remove_on_drop: bool,
+    },
+}
+
+/// A GNU make compatible jobserver.
+///
+/// A jobserver with a limit of `N` jobs holds `N - 1` tokens, as each process which participates
+/// has one implicit token which allows it to run a single job without acquiring any.
+#[derive(Debug)]
+pub struct Jobserver {
+    channel: Channel,
+    limit: Option<usize>,
+}
+
+/// The way a jobserver is advertised to a child process, parsed from its make flags.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum JobserverAuth {
+    /// The read and write ends of a pipe.
+    Fds(RawFd, RawFd),
+    /// The path of a named FIFO.
+    Fifo(PathBuf),
+}
+
+impl Jobserver {
+    /// Creates a new jobserver using an anonymous pipe, allowing up to `limit` jobs to run in
+    /// parallel.
+    pub fn new(limit: usize) -> io::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        let jobserver = Self {
+            channel: Channel::Pipe { read, write },
+            limit: Some(limit),
+        };
+        jobserver.add_tokens(limit.saturating_sub(1))?;
+        Ok(jobserver)
+    }
+
+    /// Creates a new jobserver using a named FIFO at the given path, allowing up to `limit` jobs
+    /// to run in parallel.
+    ///
+    /// Only GNU make 4.4 and later support FIFO jobservers. The FIFO is removed when the jobserver
+    /// is dropped.
+    pub fn new_fifo(path: impl Into<PathBuf>, limit: usize) -> io::Result<Self> {
+        let path = path.into();
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        let fd = open(&path, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+        let jobserver = Self {
+            channel: Channel::Fifo {
+                path,
+                fd,
+                remove_on_drop: true,
+            },
+            limit: Some(limit),
+        };
+        jobserver.add_tokens(limit.saturating_sub(1))?;
+        Ok(jobserver)
+    }
+
+    /// Creates a jobserver from the given pipe ends, e.g. inherited from a parent process.
+    pub(crate) fn from_pipe(read: OwnedFd, write: OwnedFd) -> Self {
+        Self {
+            channel: Channel::Pipe { read, write },
+            limit: None,
+        }
+    }
+
+    /// Opens an existing jobserver FIFO, e.g. advertised by a parent process.
+    pub(crate) fn open_fifo(path: &Path) -> Result<Self, Errno> {
+        let fd = open(path, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+        Ok(Self {
+            channel: Channel::Fifo {
+                path: path.to_owned(),
+                fd,
+                remove_on_drop: false,
+            },
+            limit: None,
+        })
+    }
+
+    /// Returns the job limit, if known. It isn't known for jobservers inherited from a parent
+    /// process.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Acquires a token, blocking until one is available.
+    ///
+    /// The token is returned to the jobserver when the returned guard is dropped.
+    pub fn acquire(&self) -> io::Result<Token<'_>> {
+        let mut byte = [0];
+        loop {
+            match read(self.read_fd(), &mut byte) {
+                Ok(1) => {
+                    return Ok(Token {
+                        jobserver: self,
+                        byte: byte[0],
+                    });
+                }
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Jobserver closed",
+                    ));
+                }
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns the make flags to advertise the jobserver to a child process, using the given file
+    /// descriptor numbers for the ends of a pipe.
+    pub fn makeflags(&self, read_fd: RawFd, write_fd: RawFd) -> String {
+        let jobs = match self.limit {
+            Some(limit) => format!("-j{limit}"),
+            None => "-j".to_owned(),
+        };
+        match &self.channel {
+            Channel::Pipe { .. } => format!(
+                "{jobs} --jobserver-fds={read_fd},{write_fd} --jobserver-auth={read_fd},{write_fd}"
+            ),
+            Channel::Fifo { path, .. } => {
+                format!("{jobs} --jobserver-auth=fifo:{}", path.display())
+            }
+        }
+    }
+
+    /// Returns the file descriptor mappings and environment variables to pass the jobserver to a
+    /// child process.
+    ///
+    /// For a pipe jobserver, the ends of the pipe are mapped to `read_fd` and `write_fd` in the
+    /// child. For a FIFO jobserver, no file descriptors are passed, as the child opens the FIFO by
+    /// its path. `MAKEFLAGS` and `CARGO_MAKEFLAGS` are set, replacing any existing values.
+    pub fn advertise(&self, read_fd: RawFd, write_fd: RawFd) -> io::Result<Advertisement> {
+        let mappings = match &self.channel {
+            Channel::Pipe { read, write } => vec![
+                FdMapping {
+                    parent_fd: read.try_clone()?,
+                    child_fd: read_fd,
+                },
+                FdMapping {
+                    parent_fd: write.try_clone()?,
+                    child_fd: write_fd,
+                },
+            ],
+            Channel::Fifo { .. } => Vec::new(),
+        };
+        let makeflags = self.makeflags(read_fd, write_fd);
+        Ok(Advertisement {
+            mappings,
+            env: vec![
+                ("MAKEFLAGS".to_owned(), makeflags.clone()),
+                ("CARGO_MAKEFLAGS".to_owned(), makeflags),
+            ],
+            env_remove: vec!["MFLAGS".to_owned()],
+            ..Default::default()
+        })
+    }
+
+    /// Passes the jobserver to the given command. See [`advertise`](Self::advertise).
+    ///
+    /// As with [`CommandFdExt::fd_mappings`](crate::CommandFdExt::fd_mappings), this shouldn't be
+    /// combined with other mappings on the same command.
+    pub fn configure(
+        &self,
+        command: &mut Command,
+        read_fd: RawFd,
+        write_fd: RawFd,
+    ) -> io::Result<()> {
+        self.advertise(read_fd, write_fd)?
+            .apply(command)
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn read_fd(&self) -> BorrowedFd<'_> {
+        match &self.channel {
+            Channel::Pipe { read, .. } => read.as_fd(),
+            Channel::Fifo { fd, .. } => fd.as_fd(),
+        }
+    }
+
+    fn write_fd(&self) -> BorrowedFd<'_> {
+        match &self.channel {
+            Channel::Pipe { write, .. } => write.as_fd(),
+            Channel::Fifo { fd, .. } => fd.as_fd(),
+        }
+    }
+
+    fn add_tokens(&self, count: usize) -> io::Result<()> {
+        let tokens = vec![TOKEN; count];
+        let mut written = 0;
+        while written < count {
+            match write(self.write_fd(), &tokens[written..]) {
+                Ok(n) => written += n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        if let Channel::Fifo {
+            path,
+            remove_on_drop: true,
+            ..
+        } = &self.channel
+        {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// A token acquired from a [`Jobserver`], which is returned when dropped.
+#[derive(Debug)]
+pub struct Token<'a> {
+    jobserver: &'a Jobserver,
+    byte: u8,
+}
+
+impl Drop for Token<'_> {
+    fn drop(&mut self) {
+        while let Err(Errno::EINTR) = write(self.jobserver.write_fd(), &[self.byte]) {}
+    }
+}
+
+/// Parses the jobserver advertised in the given make flags, if any. If there is more than one,
+/// the last one wins, as for make.
+pub(crate) fn parse_makeflags(makeflags: &str) -> Option<Result<JobserverAuth, ()>> {
+    let value = makeflags.split_whitespace().rev().find_map(|flag| {
+        flag.strip_prefix("--jobserver-auth=")
+            .or_else(|| flag.strip_prefix("--jobserver-fds="))
+    })?;
+    Some(if let Some(path) = value.strip_prefix("fifo:") {
+        Ok(JobserverAuth::Fifo(path.into()))
+    } else {
+        value
+            .split_once(',')
+            .and_then(|(read, write)| {
+                Some(JobserverAuth::Fds(read.parse().ok()?, write.parse().ok()?))
+            })
+            .ok_or(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_makeflags(" -j4"), None);
+        assert_eq!(
+            parse_makeflags(" -j4 --jobserver-fds=3,4 --jobserver-auth=5,6"),
+            Some(Ok(JobserverAuth::Fds(5, 6)))
+        );
+        assert_eq!(
+            parse_makeflags("-j --jobserver-fds=3,4"),
+            Some(Ok(JobserverAuth::Fds(3, 4)))
+        );
+        assert_eq!(
+            parse_makeflags("-j8 --jobserver-auth=fifo:/tmp/GMfifo123"),
+            Some(Ok(JobserverAuth::Fifo("/tmp/GMfifo123".into())))
+        );
+        assert_eq!(parse_makeflags("--jobserver-auth=3"), Some(Err(())));
+    }
+
+    #[test]
+    fn tokens() {
+        setup();
+        let jobserver = Jobserver::new(3).unwrap();
+        assert_eq!(jobserver.limit(), Some(3));
+        let first = jobserver.acquire().unwrap();
+        let second = jobserver.acquire().unwrap();
+        drop(first);
+        let third = jobserver.acquire().unwrap();
+        drop(second);
+        drop(third);
+    }
+
+    #[test]
+    fn fifo() {
+        setup();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("fifo");
+        let jobserver = Jobserver::new_fifo(&path, 2).unwrap();
+        assert_eq!(
+            jobserver.makeflags(3, 4),
+            format!("-j2 --jobserver-auth=fifo:{}", path.display())
+        );
+
+        let opened = Jobserver::open_fifo(&path).unwrap();
+        let token = opened.acquire().unwrap();
+        drop(token);
+        drop(opened);
+        assert!(path.exists());
+        drop(jobserver);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn configure_child() {
+        setup();
+        let jobserver = Jobserver::new(4).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"[ "$MAKEFLAGS" = "-j4 --jobserver-fds=5,6 --jobserver-auth=5,6" ] && [ "$CARGO_MAKEFLAGS" = "$MAKEFLAGS" ] && head -c 3 <&5 && printf +++ >&6"#,
+        );
+        jobserver.configure(&mut command, 5, 6).unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(output.stdout, b"+++");
+
+        // The child returned all the tokens it took.
+        let _tokens = (0..3)
+            .map(|_| jobserver.acquire().unwrap())
+            .collect::<Vec<_>>();
+    }
+}
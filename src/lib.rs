@@ -75,18 +75,40 @@
 //! }
 //! ```
 
+pub mod android;
+pub mod control;
+pub mod daemon;
+pub mod dir;
+pub mod fdstore;
+pub mod inetd;
 pub mod inherited;
+pub mod jobserver;
+pub mod listen;
+pub mod pipeline;
+pub mod pool;
+pub mod protocol;
+pub mod pty;
+pub mod redirect;
+pub mod reexec;
 #[cfg(feature = "tokio")]
 pub mod tokio;
+pub mod transfer;
 
-use nix::fcntl::{FcntlArg, FdFlag, fcntl};
-use nix::unistd::dup2_raw;
-use std::cmp::max;
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, FdFlag, OFlag, fcntl, open};
+use nix::libc;
+use nix::sys::stat::Mode;
+use nix::unistd::{dup2_raw, pipe2, read, write};
+use std::cmp::{max, min};
+use std::ffi::CString;
 use std::io;
-use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// A mapping from a file descriptor in the parent to a file descriptor in the child, to be applied
@@ -100,13 +122,241 @@ pub struct FdMapping {
     pub child_fd: RawFd,
 }
 
+/// An [`FdMapping`] along with an optional name for the file descriptor, which may be advertised to
+/// the child process so that it can find the file descriptor without knowing its number.
+#[derive(Debug)]
+pub struct NamedFdMapping {
+    pub name: Option<String>,
+    pub mapping: FdMapping,
+}
+
+impl From<FdMapping> for NamedFdMapping {
+    fn from(mapping: FdMapping) -> Self {
+        Self {
+            name: None,
+            mapping,
+        }
+    }
+}
+
+impl From<NamedFdMapping> for FdMapping {
+    fn from(named: NamedFdMapping) -> Self {
+        named.mapping
+    }
+}
+
+impl FdMapping {
+    /// Creates a mapping for a new file description of the same file as `fd`, as returned by
+    /// [`reopen_fd`], so that the child can't affect the offset or status flags of `fd`.
+    pub fn reopened(fd: impl AsFd, child_fd: RawFd, flags: Option<OFlag>) -> io::Result<Self> {
+        Ok(Self {
+            parent_fd: reopen_fd(fd, flags)?,
+            child_fd,
+        })
+    }
+}
+
+/// Opens a new file description for the same file as `fd`, by opening `/proc/self/fd/N`.
+///
+/// Unlike a duplicated FD, the new file description has its own offset and status flags, so
+/// seeking or setting `O_NONBLOCK` on one doesn't affect the other. If `flags` is `None` then the
+/// access mode and status flags of `fd` are used; otherwise `flags` are used instead, so for
+/// example `O_APPEND` or `O_NONBLOCK` can be cleared by leaving them out. `O_CLOEXEC` is always
+/// added.
+///
+/// The access mode may be narrowed, from read-write to read-only or write-only, but not widened.
+/// Attempting to widen it, or to open an `O_PATH` FD for reading or writing, fails with
+/// `PermissionDenied`. Some kinds of FD, such as sockets, can't be reopened at all.
+pub fn reopen_fd(fd: impl AsFd, flags: Option<OFlag>) -> io::Result<OwnedFd> {
+    let fd = fd.as_fd();
+    let current = OFlag::from_bits_retain(fcntl(fd, FcntlArg::F_GETFL)?);
+    let flags = flags.unwrap_or(current);
+
+    let current_access = current & OFlag::O_ACCMODE;
+    let access = flags & OFlag::O_ACCMODE;
+    let allowed = if current.contains(OFlag::O_PATH) {
+        flags.contains(OFlag::O_PATH)
+    } else {
+        flags.contains(OFlag::O_PATH) || access == current_access || current_access == OFlag::O_RDWR
+    };
+    if !allowed {
+        return Err(Errno::EACCES.into());
+    }
+
+    Ok(open(
+        format!("/proc/self/fd/{}", fd.as_raw_fd()).as_str(),
+        flags | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?)
+}
+
+/// An operation on the file descriptors of a child process, more general than an [`FdMapping`].
+///
+/// When applied with [`CommandFdExt::fd_actions`], all `Map` and `Open` actions are applied first,
+/// then all `Dup` actions, then all `Close` actions. This all happens after [`Command`] has set up the
+/// child's standard streams, so a `Dup` of FD 1 refers to whatever the child's stdout ends up
+/// being.
+#[derive(Debug)]
+pub enum FdAction {
+    /// Maps a single parent FD to one or more child FDs, without needing to duplicate it in the
+    /// parent first.
+    Map {
+        parent_fd: OwnedFd,
+        child_fds: Vec<RawFd>,
+    },
+    /// Opens a file in the child process as `child_fd`, so that the parent never holds it.
+    Open { file: ChildOpen, child_fd: RawFd },
+    /// Makes `child_fd` a duplicate of the child FD `source`, as it is after the standard streams
+    /// are set up and all `Map` actions are applied. If `source` and `child_fd` are the same then
+    /// the inherited FD is just kept open.
+    Dup { source: RawFd, child_fd: RawFd },
+    /// Ensures that the child FD is closed, whether or not it would otherwise be inherited.
+    Close(RawFd),
+}
+
+impl From<FdMapping> for FdAction {
+    fn from(mapping: FdMapping) -> Self {
+        Self::Map {
+            parent_fd: mapping.parent_fd,
+            child_fds: vec![mapping.child_fd],
+        }
+    }
+}
+
+/// A file to be opened by the child process, after it has forked but before it execs.
+///
+/// The path is converted to a C string when this is created, so that opening it doesn't need to
+/// allocate. Because it is opened after any `pre_exec` hooks registered earlier, a relative path is
+/// resolved relative to any directory they change to.
+///
+/// [`Command::spawn`] only reports the error code if the open fails, so the child also reports the
+/// failure back through a pipe. Clones of a `ChildOpen` share this pipe, so a clone can be kept to
+/// find out with [`take_error`](Self::take_error) whether it was this file which failed to open.
+#[derive(Clone, Debug)]
+pub struct ChildOpen {
+    inner: Arc<ChildOpenInner>,
+}
+
+#[derive(Debug)]
+struct ChildOpenInner {
+    path: PathBuf,
+    c_path: CString,
+    flags: OFlag,
+    mode: Mode,
+    error_reader: OwnedFd,
+    error_writer: OwnedFd,
+}
+
+impl ChildOpen {
+    /// Prepares to open the given path with the given flags, and the given mode if the file is
+    /// created.
+    ///
+    /// `O_CLOEXEC` is added to the flags while opening the file, but is not set on the child FD.
+    /// Returns an error if the path contains a NUL byte, or the pipe for reporting errors can't be
+    /// created.
+    pub fn new(path: impl Into<PathBuf>, flags: OFlag, mode: Mode) -> io::Result<Self> {
+        let path = path.into();
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let (error_reader, error_writer) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        Ok(Self {
+            inner: Arc::new(ChildOpenInner {
+                path,
+                c_path,
+                flags,
+                mode,
+                error_reader,
+                error_writer,
+            }),
+        })
+    }
+
+    /// Returns the path of the file to be opened.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Returns the error with which a child process most recently failed to open the file, if it
+    /// has failed since the last call.
+    pub fn take_error(&self) -> Option<ChildOpenError> {
+        let mut errno = None;
+        let mut buf = [0; size_of::<i32>()];
+        while read(&self.inner.error_reader, &mut buf) == Ok(buf.len()) {
+            errno = Some(i32::from_ne_bytes(buf));
+        }
+        errno.map(|errno| ChildOpenError {
+            path: self.inner.path.clone(),
+            source: io::Error::from_raw_os_error(errno),
+        })
+    }
+
+    // This function must not do any allocation, as it is called from the pre_exec hook.
+    fn open(&self, child_fd: RawFd) -> io::Result<()> {
+        let inner = &self.inner;
+        let fd = match open(
+            inner.c_path.as_c_str(),
+            inner.flags | OFlag::O_CLOEXEC,
+            inner.mode,
+        ) {
+            Ok(fd) => fd,
+            Err(errno) => {
+                let _ = write(&inner.error_writer, &(errno as i32).to_ne_bytes());
+                return Err(errno.into());
+            }
+        };
+        if fd.as_raw_fd() == child_fd {
+            fcntl(&fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            let _ = fd.into_raw_fd();
+        } else {
+            // SAFETY: As in `apply_child_fd_ops`, the new child FD isn't owned by anything in
+            // this process.
+            Errno::result(unsafe { libc::dup2(fd.as_raw_fd(), child_fd) })?;
+        }
+        Ok(())
+    }
+}
+
+/// Error opening a file in a child process, as returned by [`ChildOpen::take_error`].
+#[derive(Debug, Error)]
+#[error("Failed to open {path:?} in child process: {source}")]
+pub struct ChildOpenError {
+    /// The path of the file which couldn't be opened.
+    pub path: PathBuf,
+    /// The underlying error.
+    pub source: io::Error,
+}
+
 /// Error setting up FD mappings, because there were two or more mappings for the same child FD.
 #[derive(Copy, Clone, Debug, Eq, Error, PartialEq)]
 #[error("Two or more mappings for the same child FD")]
 pub struct FdMappingCollision;
 
+/// Error setting up FD actions.
+#[derive(Copy, Clone, Debug, Eq, Error, PartialEq)]
+pub enum FdActionError {
+    /// Two or more actions for the same child FD
+    #[error(transparent)]
+    Collision(#[from] FdMappingCollision),
+
+    /// An action refers to a negative FD number
+    #[error("Invalid FD number {0}")]
+    InvalidFd(RawFd),
+}
+
+mod private {
+    /// Prevents [`CommandFdExt`](super::CommandFdExt) from being implemented outside this crate,
+    /// so that methods can be added to it without breaking changes.
+    pub trait Sealed {}
+
+    impl Sealed for std::process::Command {}
+
+    #[cfg(feature = "tokio")]
+    impl Sealed for tokio::process::Command {}
+}
+
 /// Extension to add file descriptor mappings to a [`Command`].
-pub trait CommandFdExt {
+///
+/// This is sealed, so it can't be implemented outside this crate.
+pub trait CommandFdExt: private::Sealed {
     /// Adds the given set of file descriptors to the command.
     ///
     /// Warning: Calling this more than once on the same command may result in unexpected behaviour.
@@ -124,6 +374,18 @@ pub trait CommandFdExt {
     /// Note that the `Command` takes ownership of the file descriptors, which means that they won't
     /// be closed in the parent process until the `Command` is dropped.
     fn preserved_fds(&mut self, fds: Vec<OwnedFd>) -> &mut Self;
+
+    /// Adds the given set of actions on the child's file descriptors to the command.
+    ///
+    /// Returns an error if two actions target the same child FD, or if a `Dup` duplicates a child
+    /// FD which is the target of another `Dup`, as the result would then depend on the order in
+    /// which they were applied. Also returns an error if any action refers to a negative FD
+    /// number. The same warning applies as for
+    /// [`fd_mappings`](Self::fd_mappings) about calling this more than once.
+    ///
+    /// Note that the `Command` takes ownership of the file descriptors, which means that they won't
+    /// be closed in the parent process until the `Command` is dropped.
+    fn fd_actions(&mut self, actions: Vec<FdAction>) -> Result<&mut Self, FdActionError>;
 }
 
 impl CommandFdExt for Command {
@@ -152,6 +414,17 @@ impl CommandFdExt for Command {
 
         self
     }
+
+    fn fd_actions(&mut self, actions: Vec<FdAction>) -> Result<&mut Self, FdActionError> {
+        let mut ops = prepare_fd_actions(actions)?;
+
+        // Safety: `ChildFdOps::apply` will not allocate, so it is safe to call from this hook.
+        unsafe {
+            self.pre_exec(move || ops.apply());
+        }
+
+        Ok(self)
+    }
 }
 
 /// Validates that there are no conflicting mappings to the same child FD.
@@ -218,6 +491,203 @@ fn map_fds(mappings: &mut [FdMapping], child_fds: &[RawFd]) -> io::Result<()> {
     Ok(())
 }
 
+/// Validates the given actions, and converts them to the equivalent [`ChildFdOps`].
+fn prepare_fd_actions(actions: Vec<FdAction>) -> Result<ChildFdOps, FdActionError> {
+    let mut parent_fds = Vec::new();
+    let mut child_opens = Vec::new();
+    let mut maps = Vec::new();
+    let mut dups = Vec::new();
+    let mut closes = Vec::new();
+    for action in actions {
+        match action {
+            FdAction::Map {
+                parent_fd,
+                child_fds,
+            } => {
+                maps.extend(child_fds.into_iter().map(|child_fd| ChildFdOp::Map {
+                    parent_index: parent_fds.len(),
+                    child_fd,
+                }));
+                parent_fds.push(parent_fd);
+            }
+            FdAction::Open { file, child_fd } => {
+                maps.push(ChildFdOp::Open {
+                    open_index: child_opens.len(),
+                    child_fd,
+                });
+                child_opens.push(file);
+            }
+            FdAction::Dup { source, child_fd } => dups.push(ChildFdOp::Dup {
+                source,
+                target: child_fd,
+            }),
+            FdAction::Close(fd) => closes.push(ChildFdOp::Close(fd)),
+        }
+    }
+
+    let targets = |ops: &[ChildFdOp]| -> Vec<RawFd> {
+        ops.iter()
+            .map(|op| match *op {
+                ChildFdOp::Map { child_fd, .. } | ChildFdOp::Open { child_fd, .. } => child_fd,
+                ChildFdOp::Dup { target, .. } => target,
+                ChildFdOp::Close(fd) => fd,
+            })
+            .collect()
+    };
+    // The child side uses these numbers directly with `dup2` and `close`, so they must be valid.
+    if let Some(fd) = [&maps, &dups, &closes]
+        .into_iter()
+        .flatten()
+        .map(ChildFdOp::min_fd)
+        .find(|fd| *fd < 0)
+    {
+        return Err(FdActionError::InvalidFd(fd));
+    }
+
+    let mut all_targets = [targets(&maps), targets(&dups), targets(&closes)].concat();
+    let count = all_targets.len();
+    all_targets.sort_unstable();
+    all_targets.dedup();
+    if all_targets.len() != count {
+        return Err(FdMappingCollision.into());
+    }
+    let dup_targets = targets(&dups);
+    for dup in &dups {
+        if let ChildFdOp::Dup { source, target } = *dup
+            && source != target
+            && dup_targets.contains(&source)
+        {
+            return Err(FdMappingCollision.into());
+        }
+    }
+
+    Ok(ChildFdOps {
+        parent_fds,
+        child_opens,
+        ops: [maps, dups, closes].concat(),
+    })
+}
+
+/// An operation on the file descriptors of a child process, to be applied in order by
+/// [`ChildFdOps::apply`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ChildFdOp {
+    /// Duplicates the parent FD with the given index onto the child FD.
+    Map {
+        parent_index: usize,
+        child_fd: RawFd,
+    },
+    /// Opens the [`ChildOpen`] with the given index as the child FD.
+    Open { open_index: usize, child_fd: RawFd },
+    /// Duplicates whatever the child FD `source` currently is onto the child FD `target`.
+    Dup { source: RawFd, target: RawFd },
+    /// Closes the child FD, if it is open.
+    Close(RawFd),
+}
+
+impl ChildFdOp {
+    /// Returns the lowest FD number which the operation refers to.
+    fn min_fd(&self) -> RawFd {
+        match *self {
+            Self::Map { child_fd, .. } | Self::Open { child_fd, .. } => child_fd,
+            Self::Dup { source, target } => min(source, target),
+            Self::Close(fd) => fd,
+        }
+    }
+
+    /// Returns the highest FD number which the operation refers to.
+    fn max_fd(&self) -> RawFd {
+        match *self {
+            Self::Map { child_fd, .. } | Self::Open { child_fd, .. } => child_fd,
+            Self::Dup { source, target } => max(source, target),
+            Self::Close(fd) => fd,
+        }
+    }
+}
+
+/// A sequence of operations on the file descriptors of a child process, along with the parent FDs
+/// and files to open which they refer to.
+#[derive(Debug, Default)]
+pub(crate) struct ChildFdOps {
+    pub(crate) parent_fds: Vec<OwnedFd>,
+    pub(crate) child_opens: Vec<ChildOpen>,
+    pub(crate) ops: Vec<ChildFdOp>,
+}
+
+impl ChildFdOps {
+    /// Applies the operations in order, like the redirections of a shell command.
+    ///
+    /// The parent FDs are first moved clear of every FD number referred to by the operations, so
+    /// that no operation can clobber a parent FD which a later operation needs, and so that a `Dup`
+    /// can't accidentally refer to one of the parent FDs rather than a child FD.
+    // This function must not do any allocation, as it is called from the pre_exec hook.
+    pub(crate) fn apply(&mut self) -> io::Result<()> {
+        apply_child_fd_ops(&mut self.parent_fds, &self.child_opens, &self.ops)
+    }
+}
+
+// This function must not do any allocation, as it is called from the pre_exec hook.
+fn apply_child_fd_ops(
+    parent_fds: &mut [OwnedFd],
+    child_opens: &[ChildOpen],
+    ops: &[ChildFdOp],
+) -> io::Result<()> {
+    let first_safe_fd = parent_fds
+        .iter()
+        .map(AsRawFd::as_raw_fd)
+        .chain(ops.iter().map(ChildFdOp::max_fd))
+        .max()
+        .map_or(0, |fd| fd + 1);
+
+    for parent_fd in parent_fds.iter_mut() {
+        let moved_fd = fcntl(&*parent_fd, FcntlArg::F_DUPFD_CLOEXEC(first_safe_fd))?;
+        // SAFETY: We just created `moved_fd` so we can take ownership of it. The old FD is closed
+        // when it is replaced.
+        unsafe {
+            *parent_fd = OwnedFd::from_raw_fd(moved_fd);
+        }
+    }
+
+    for op in ops {
+        match *op {
+            // The child FDs are just numbers which we don't own, so these use the raw syscalls
+            // rather than constructing owned or borrowed FDs for them.
+            ChildFdOp::Map {
+                parent_index,
+                child_fd,
+            } => {
+                // SAFETY: `dup2` accepts any integer as the target, and closes it first if it is
+                // open. The new child FD isn't owned by anything in this process.
+                Errno::result(unsafe {
+                    libc::dup2(parent_fds[parent_index].as_raw_fd(), child_fd)
+                })?;
+            }
+            ChildFdOp::Open {
+                open_index,
+                child_fd,
+            } => child_opens[open_index].open(child_fd)?,
+            ChildFdOp::Dup { source, target } => {
+                // SAFETY: `fcntl` and `dup2` accept any integers, and fail with `EBADF` if the
+                // source isn't open.
+                if source == target {
+                    Errno::result(unsafe { libc::fcntl(source, libc::F_SETFD, 0) })?;
+                } else {
+                    Errno::result(unsafe { libc::dup2(source, target) })?;
+                }
+            }
+            ChildFdOp::Close(fd) => {
+                // Closing an FD which isn't open is fine, like `n>&-` in a shell.
+                // SAFETY: Nothing else in the child refers to the FD after this point.
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn preserve_fds(fds: &[OwnedFd]) -> io::Result<()> {
     for fd in fds {
         // Remove the FD_CLOEXEC flag, so the FD will be kept open when exec is called for the
@@ -234,8 +704,9 @@ mod tests {
     use nix::unistd::close;
     use std::collections::HashSet;
     use std::fs::{File, read_dir};
+    use std::io::Read;
     use std::os::unix::io::AsRawFd;
-    use std::process::Output;
+    use std::process::{Output, Stdio};
     use std::str;
     use std::sync::Once;
 
@@ -420,6 +891,253 @@ mod tests {
         assert_eq!(output.stdout, b"test 1");
     }
 
+    #[test]
+    fn fan_out_dup_and_close() {
+        setup();
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("cat <&3; cat <&4; echo out >&5; echo err >&2; ls /proc/self/fd/0 2>/dev/null");
+        command.stderr(Stdio::piped());
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        assert!(
+            command
+                .fd_actions(vec![
+                    FdAction::Map {
+                        parent_fd: file.into(),
+                        child_fds: vec![3, 4],
+                    },
+                    // Stdout is a pipe set up by `Command`, so this duplicates the pipe.
+                    FdAction::Dup {
+                        source: 1,
+                        child_fd: 5,
+                    },
+                    FdAction::Dup {
+                        source: 1,
+                        child_fd: 2,
+                    },
+                    // Stdin would otherwise be inherited from the parent.
+                    FdAction::Close(0),
+                ])
+                .is_ok()
+        );
+
+        let output = command.output().unwrap();
+        assert!(!output.status.success());
+        // Both FDs 3 and 4 share the same file description, so the second read starts at the end.
+        assert_eq!(
+            output.stdout,
+            b"test 1out
+err
+"
+        );
+        assert_eq!(output.stderr, b"");
+    }
+
+    #[test]
+    fn conflicting_actions() {
+        setup();
+
+        let file = || File::open("testdata/file1.txt").unwrap().into();
+
+        for actions in [
+            vec![FdAction::Map {
+                parent_fd: file(),
+                child_fds: vec![3, 3],
+            }],
+            vec![
+                FdMapping {
+                    parent_fd: file(),
+                    child_fd: 3,
+                }
+                .into(),
+                FdAction::Close(3),
+            ],
+            vec![
+                FdAction::Dup {
+                    source: 1,
+                    child_fd: 3,
+                },
+                FdAction::Dup {
+                    source: 2,
+                    child_fd: 3,
+                },
+            ],
+            vec![
+                FdAction::Dup {
+                    source: 1,
+                    child_fd: 3,
+                },
+                FdAction::Dup {
+                    source: 3,
+                    child_fd: 4,
+                },
+            ],
+        ] {
+            assert_eq!(
+                Command::new("ls").fd_actions(actions).unwrap_err(),
+                FdActionError::Collision(FdMappingCollision)
+            );
+        }
+    }
+
+    #[test]
+    fn negative_action_fds() {
+        setup();
+
+        let file = || File::open("testdata/file1.txt").unwrap().into();
+
+        for (actions, fd) in [
+            (
+                vec![FdAction::Map {
+                    parent_fd: file(),
+                    child_fds: vec![3, -1],
+                }],
+                -1,
+            ),
+            (
+                vec![FdAction::Dup {
+                    source: -2,
+                    child_fd: 3,
+                }],
+                -2,
+            ),
+            (vec![FdAction::Close(-3)], -3),
+        ] {
+            assert_eq!(
+                Command::new("ls").fd_actions(actions).unwrap_err(),
+                FdActionError::InvalidFd(fd)
+            );
+        }
+    }
+
+    #[test]
+    fn open_in_child() {
+        setup();
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_owned();
+        let log = ChildOpen::new(
+            "log",
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+            Mode::from_bits_truncate(0o600),
+        )
+        .unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo hello >&3");
+        // The relative path is opened after this hook changes directory.
+        unsafe {
+            command.pre_exec(move || std::env::set_current_dir(&dir_path));
+        }
+        assert!(
+            command
+                .fd_actions(vec![FdAction::Open {
+                    file: log.clone(),
+                    child_fd: 3,
+                }])
+                .is_ok()
+        );
+
+        assert!(command.status().unwrap().success());
+        assert!(log.take_error().is_none());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("log")).unwrap(),
+            "hello\n"
+        );
+    }
+
+    #[test]
+    fn open_in_child_failure() {
+        setup();
+
+        let readable =
+            ChildOpen::new("testdata/file1.txt", OFlag::O_RDONLY, Mode::empty()).unwrap();
+        let missing = ChildOpen::new("/nonexistent/file", OFlag::O_RDONLY, Mode::empty()).unwrap();
+
+        let mut command = Command::new("true");
+        assert!(
+            command
+                .fd_actions(vec![
+                    FdAction::Open {
+                        file: readable.clone(),
+                        child_fd: 3,
+                    },
+                    FdAction::Open {
+                        file: missing.clone(),
+                        child_fd: 4,
+                    },
+                ])
+                .is_ok()
+        );
+
+        let error = command.spawn().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(readable.take_error().is_none());
+        let open_error = missing.take_error().unwrap();
+        assert_eq!(open_error.path, Path::new("/nonexistent/file"));
+        assert_eq!(open_error.source.kind(), io::ErrorKind::NotFound);
+        // The error is only reported once.
+        assert!(missing.take_error().is_none());
+    }
+
+    #[test]
+    fn reopen() {
+        setup();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "0123456789").unwrap();
+        let mut file = File::options().read(true).append(true).open(&path).unwrap();
+
+        // The reopened file has its own offset and status flags.
+        let mut buf = [0; 4];
+        file.read_exact(&mut buf).unwrap();
+        let mut reopened = File::from(reopen_fd(&file, None).unwrap());
+        reopened.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"0123");
+        let flags = OFlag::from_bits_retain(fcntl(&reopened, FcntlArg::F_GETFL).unwrap());
+        assert!(flags.contains(OFlag::O_APPEND | OFlag::O_RDWR));
+        fcntl(&reopened, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)).unwrap();
+        let flags = OFlag::from_bits_retain(fcntl(&file, FcntlArg::F_GETFL).unwrap());
+        assert!(!flags.contains(OFlag::O_NONBLOCK));
+
+        // The access mode can be narrowed and O_APPEND cleared, but not widened again.
+        let read_only = reopen_fd(&file, Some(OFlag::O_RDONLY)).unwrap();
+        let flags = OFlag::from_bits_retain(fcntl(&read_only, FcntlArg::F_GETFL).unwrap());
+        assert_eq!(flags & OFlag::O_ACCMODE, OFlag::O_RDONLY);
+        assert!(!flags.contains(OFlag::O_APPEND));
+        assert_eq!(
+            reopen_fd(&read_only, Some(OFlag::O_RDWR))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            reopen_fd(&read_only, Some(OFlag::O_WRONLY))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        // The child reads from the start of the file, regardless of the parent's offset.
+        let mut command = Command::new("cat");
+        assert!(
+            command
+                .fd_mappings(vec![
+                    FdMapping::reopened(&file, 0, Some(OFlag::O_RDONLY)).unwrap()
+                ])
+                .is_ok()
+        );
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"0123456789");
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"4567");
+    }
+
     /// Parse the output of ls into a set of filenames
     fn parse_ls_output(output: &[u8]) -> HashSet<String> {
         str::from_utf8(output)
@@ -443,7 +1161,11 @@ mod tests {
         }
     }
 
-    fn setup() {
+    /// Closes any excess file descriptors the first time it is called.
+    ///
+    /// This must be called at the start of any test which opens file descriptors, so that they
+    /// aren't closed from under it.
+    pub(crate) fn setup() {
         SETUP.call_once(close_excess_fds);
     }
 
@@ -0,0 +1,574 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opening listening sockets and FIFOs from textual specs, to pass to child processes.
+//!
+//! A spec has the form `[name=]kind:address[,option...]`, where `kind` is one of:
+//!
+//! - `tcp`, with an address like `127.0.0.1:8080` or `[::]:8080`;
+//! - `udp`, with an address like `0.0.0.0:53` or `[::]:53`;
+//! - `unix`, with a filesystem path like `/run/app.sock`;
+//! - `unix-abstract`, with a name in the Linux abstract socket namespace like `app`;
+//! - `fifo`, with a filesystem path like `/run/ctl`.
+//!
+//! The supported options are:
+//!
+//! - `backlog=N` to set the listen backlog of stream sockets, which defaults to `SOMAXCONN`;
+//! - `reuseport` to set `SO_REUSEPORT` on TCP and UDP sockets;
+//! - `mode=MODE` to set the octal permissions of a Unix socket or FIFO;
+//! - `owner=UID` and `group=GID` to set the numeric owner and group of a Unix socket or FIFO.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::listen::map_listeners;
+//! use std::process::Command;
+//!
+//! let mut command = Command::new("server");
+//! map_listeners(
+//!     &mut command,
+//!     ["http=tcp:0.0.0.0:8080,backlog=128", "admin=unix:/run/server.sock,mode=0600"],
+//!     3,
+//! )
+//! .unwrap();
+//! let mut child = command.spawn().unwrap();
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, NamedFdMapping,
+    inherited::{FD_NAMES_ENV_VAR, format_fd_names},
+};
+use nix::{
+    errno::Errno,
+    fcntl::{F_GETFL, F_SETFL, OFlag, fcntl, open},
+    sys::{
+        socket::{
+            AddressFamily, Backlog, SockFlag, SockType, SockaddrStorage, UnixAddr, bind, listen,
+            setsockopt, socket, sockopt,
+        },
+        stat::Mode,
+    },
+    unistd::mkfifo,
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{Permissions, remove_file, set_permissions, symlink_metadata},
+    io,
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileTypeExt, PermissionsExt, chown},
+            net::UnixStream,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Errors that can occur while parsing a listener spec or opening the listener.
+#[derive(Debug, Error)]
+pub enum ListenError {
+    /// The listener spec couldn't be parsed
+    #[error("Invalid listener spec {spec:?}: {reason}")]
+    InvalidSpec {
+        /// The spec which couldn't be parsed.
+        spec: String,
+        /// Why it couldn't be parsed.
+        reason: String,
+    },
+
+    /// Error creating or binding the socket or FIFO
+    #[error("Failed to open listener {spec}: {source}")]
+    Io {
+        /// The spec of the listener which couldn't be opened.
+        spec: String,
+        /// The underlying error.
+        source: io::Error,
+    },
+
+    /// Two or more listeners were mapped to the same child FD
+    #[error("Two or more mappings for the same child FD")]
+    Collision,
+}
+
+/// The kind and address of a listener.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddress {
+    /// A TCP socket listening on the given address.
+    Tcp(SocketAddr),
+    /// A UDP socket bound to the given address.
+    Udp(SocketAddr),
+    /// A Unix domain stream socket listening on the given path.
+    Unix(PathBuf),
+    /// A Unix domain stream socket listening on the given name in the abstract namespace.
+    UnixAbstract(String),
+    /// A FIFO at the given path, opened for reading and writing.
+    Fifo(PathBuf),
+}
+
+/// A parsed listener spec, describing a socket or FIFO to open.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListenerSpec {
+    /// The name to advertise the listener under, if any.
+    pub name: Option<String>,
+    /// The kind and address of the listener.
+    pub address: ListenAddress,
+    /// The listen backlog for stream sockets. Defaults to `SOMAXCONN`.
+    pub backlog: Option<i32>,
+    /// Whether to set `SO_REUSEPORT` on TCP and UDP sockets.
+    pub reuse_port: bool,
+    /// The permissions to set on a Unix socket or FIFO.
+    pub mode: Option<u32>,
+    /// The user ID to set as the owner of a Unix socket or FIFO.
+    pub owner: Option<u32>,
+    /// The group ID to set as the group of a Unix socket or FIFO.
+    pub group: Option<u32>,
+}
+
+impl ListenerSpec {
+    /// Creates a spec for the given address with no name and default options.
+    pub fn new(address: ListenAddress) -> Self {
+        Self {
+            name: None,
+            address,
+            backlog: None,
+            reuse_port: false,
+            mode: None,
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Creates and binds the socket or FIFO described by the spec.
+    ///
+    /// Any existing socket at the path of a Unix socket is removed first, unless something is
+    /// still listening on it. An existing FIFO is reused.
+    pub fn open(&self) -> Result<OwnedFd, ListenError> {
+        self.open_inner().map_err(|source| ListenError::Io {
+            spec: self.to_string(),
+            source,
+        })
+    }
+
+    /// Opens the listener, and returns a mapping to pass it to a child process as `child_fd`.
+    pub fn mapping(&self, child_fd: RawFd) -> Result<NamedFdMapping, ListenError> {
+        Ok(NamedFdMapping {
+            name: self.name.clone(),
+            mapping: FdMapping {
+                parent_fd: self.open()?,
+                child_fd,
+            },
+        })
+    }
+
+    fn open_inner(&self) -> io::Result<OwnedFd> {
+        match &self.address {
+            ListenAddress::Tcp(address) => {
+                let fd = self.inet_socket(address, SockType::Stream)?;
+                self.listen(&fd)?;
+                Ok(fd)
+            }
+            ListenAddress::Udp(address) => self.inet_socket(address, SockType::Datagram),
+            ListenAddress::Unix(path) => {
+                remove_stale_socket(path)?;
+                let fd = unix_socket()?;
+                bind(fd.as_raw_fd(), &UnixAddr::new(path)?)?;
+                self.set_ownership(path)?;
+                self.listen(&fd)?;
+                Ok(fd)
+            }
+            ListenAddress::UnixAbstract(name) => {
+                let fd = unix_socket()?;
+                bind(fd.as_raw_fd(), &UnixAddr::new_abstract(name.as_bytes())?)?;
+                self.listen(&fd)?;
+                Ok(fd)
+            }
+            ListenAddress::Fifo(path) => {
+                match mkfifo(path.as_path(), Mode::from_bits_truncate(0o666)) {
+                    Ok(()) | Err(Errno::EEXIST) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                // Opening a FIFO blocks until the other end is opened, so open it non-blocking and
+                // then clear the flag, as the child won't expect its reads to fail with EAGAIN.
+                let fd = open(
+                    path.as_path(),
+                    OFlag::O_RDWR | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+                    Mode::empty(),
+                )?;
+                let flags = OFlag::from_bits_retain(fcntl(&fd, F_GETFL)?);
+                fcntl(&fd, F_SETFL(flags - OFlag::O_NONBLOCK))?;
+                self.set_ownership(path)?;
+                Ok(fd)
+            }
+        }
+    }
+
+    fn inet_socket(&self, address: &SocketAddr, sock_type: SockType) -> io::Result<OwnedFd> {
+        let family = match address {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+        let fd = socket(family, sock_type, SockFlag::SOCK_CLOEXEC, None)?;
+        setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+        if self.reuse_port {
+            setsockopt(&fd, sockopt::ReusePort, &true)?;
+        }
+        bind(fd.as_raw_fd(), &SockaddrStorage::from(*address))?;
+        Ok(fd)
+    }
+
+    fn listen(&self, fd: &OwnedFd) -> io::Result<()> {
+        let backlog = match self.backlog {
+            Some(backlog) => Backlog::new(backlog)?,
+            None => Backlog::MAXCONN,
+        };
+        listen(fd, backlog)?;
+        Ok(())
+    }
+
+    fn set_ownership(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            chown(path, self.owner, self.group)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ListenerSpec {
+    type Err = ListenError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| ListenError::InvalidSpec {
+            spec: spec.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let mut parts = spec.split(',');
+        let main = parts.next().unwrap_or_default();
+        let (kind, rest) = main
+            .split_once(':')
+            .ok_or_else(|| invalid("missing kind"))?;
+        let (name, kind) = match kind.split_once('=') {
+            Some((name, kind)) => {
+                if name.is_empty() {
+                    return Err(invalid("empty name"));
+                }
+                (Some(name.to_owned()), kind)
+            }
+            None => (None, kind),
+        };
+        if rest.is_empty() {
+            return Err(invalid("missing address"));
+        }
+
+        let address = match kind {
+            "tcp" => ListenAddress::Tcp(rest.parse().map_err(|_| invalid("invalid address"))?),
+            "udp" => ListenAddress::Udp(rest.parse().map_err(|_| invalid("invalid address"))?),
+            "unix" => ListenAddress::Unix(rest.into()),
+            "unix-abstract" => ListenAddress::UnixAbstract(rest.to_owned()),
+            "fifo" => ListenAddress::Fifo(rest.into()),
+            _ => return Err(invalid("unknown kind")),
+        };
+        let is_inet = matches!(address, ListenAddress::Tcp(_) | ListenAddress::Udp(_));
+        let has_path = matches!(address, ListenAddress::Unix(_) | ListenAddress::Fifo(_));
+        let is_stream = !matches!(address, ListenAddress::Udp(_) | ListenAddress::Fifo(_));
+
+        let mut listener = ListenerSpec::new(address);
+        listener.name = name;
+        for option in parts {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            match (key, value) {
+                ("backlog", Some(value)) if is_stream => {
+                    listener.backlog = Some(value.parse().map_err(|_| invalid("invalid backlog"))?);
+                }
+                ("reuseport", None) if is_inet => listener.reuse_port = true,
+                ("mode", Some(value)) if has_path => {
+                    listener.mode =
+                        Some(u32::from_str_radix(value, 8).map_err(|_| invalid("invalid mode"))?);
+                }
+                ("owner", Some(value)) if has_path => {
+                    listener.owner = Some(value.parse().map_err(|_| invalid("invalid owner"))?);
+                }
+                ("group", Some(value)) if has_path => {
+                    listener.group = Some(value.parse().map_err(|_| invalid("invalid group"))?);
+                }
+                _ => return Err(invalid(&format!("unsupported option {option:?}"))),
+            }
+        }
+        Ok(listener)
+    }
+}
+
+impl Display for ListenerSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name}=")?;
+        }
+        match &self.address {
+            ListenAddress::Tcp(address) => write!(f, "tcp:{address}")?,
+            ListenAddress::Udp(address) => write!(f, "udp:{address}")?,
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display())?,
+            ListenAddress::UnixAbstract(name) => write!(f, "unix-abstract:{name}")?,
+            ListenAddress::Fifo(path) => write!(f, "fifo:{}", path.display())?,
+        }
+        if let Some(backlog) = self.backlog {
+            write!(f, ",backlog={backlog}")?;
+        }
+        if self.reuse_port {
+            write!(f, ",reuseport")?;
+        }
+        if let Some(mode) = self.mode {
+            write!(f, ",mode={mode:04o}")?;
+        }
+        if let Some(owner) = self.owner {
+            write!(f, ",owner={owner}")?;
+        }
+        if let Some(group) = self.group {
+            write!(f, ",group={group}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses and opens the given listener specs, and returns mappings to pass them to a child process
+/// on consecutive file descriptors starting at `first_fd`.
+pub fn open_listeners<S: AsRef<str>>(
+    specs: impl IntoIterator<Item = S>,
+    first_fd: RawFd,
+) -> Result<Vec<NamedFdMapping>, ListenError> {
+    let specs = specs
+        .into_iter()
+        .map(|spec| spec.as_ref().parse())
+        .collect::<Result<Vec<ListenerSpec>, _>>()?;
+    specs
+        .iter()
+        .zip(first_fd..)
+        .map(|(spec, child_fd)| spec.mapping(child_fd))
+        .collect()
+}
+
+/// Parses and opens the given listener specs, and adds them to the command on consecutive file
+/// descriptors starting at `first_fd`, with their names in the [`FD_NAMES_ENV_VAR`] environment
+/// variable.
+///
+/// As with [`CommandFdExt::fd_mappings`], this shouldn't be combined with other mappings on the
+/// same command.
+pub fn map_listeners<S: AsRef<str>>(
+    command: &mut Command,
+    specs: impl IntoIterator<Item = S>,
+    first_fd: RawFd,
+) -> Result<(), ListenError> {
+    let listeners = open_listeners(specs, first_fd)?;
+    command.env(FD_NAMES_ENV_VAR, format_fd_names(&listeners));
+    command
+        .fd_mappings(listeners.into_iter().map(Into::into).collect())
+        .map_err(|_| ListenError::Collision)?;
+    Ok(())
+}
+
+/// Removes the file at the given path if it is a socket which nothing is listening on, so that a
+/// new socket can be bound there.
+///
+/// Fails with [`io::ErrorKind::AddrInUse`] if something is still listening on the socket.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            )),
+            Err(e) if e.raw_os_error() == Some(Errno::ECONNREFUSED as i32) => remove_file(path),
+            Err(e) => Err(e),
+        },
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn unix_socket() -> io::Result<OwnedFd> {
+    Ok(socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use nix::sys::socket::{getsockname, getsockopt};
+    use std::{
+        fs::metadata,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream, UdpSocket},
+        os::unix::net::{UnixListener, UnixStream},
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn parse_and_display() {
+        for spec in [
+            "tcp:127.0.0.1:8080",
+            "http=tcp:[::]:80,backlog=16,reuseport",
+            "udp:0.0.0.0:53,reuseport",
+            "unix:/run/app.sock,mode=0660,owner=0,group=0",
+            "app=unix-abstract:app",
+            "ctl=fifo:/run/ctl,mode=0600",
+        ] {
+            assert_eq!(spec.parse::<ListenerSpec>().unwrap().to_string(), spec);
+        }
+
+        let spec: ListenerSpec = "http=tcp:127.0.0.1:80,backlog=5".parse().unwrap();
+        assert_eq!(spec.name.as_deref(), Some("http"));
+        assert_eq!(
+            spec.address,
+            ListenAddress::Tcp("127.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(spec.backlog, Some(5));
+
+        for spec in [
+            "tcp",
+            "tcp:",
+            "=tcp:127.0.0.1:80",
+            "sctp:127.0.0.1:80",
+            "tcp:localhost:80",
+            "tcp:127.0.0.1:80,mode=0600",
+            "udp:127.0.0.1:53,backlog=5",
+            "unix:/run/app.sock,reuseport",
+            "unix:/run/app.sock,mode=999",
+            "fifo:/run/ctl,frobnicate",
+        ] {
+            assert!(
+                matches!(
+                    spec.parse::<ListenerSpec>(),
+                    Err(ListenError::InvalidSpec { .. })
+                ),
+                "{spec:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn open_tcp_and_udp() {
+        setup();
+        let tcp: ListenerSpec = "tcp:127.0.0.1:0,reuseport".parse().unwrap();
+        let listener = TcpListener::from(tcp.open().unwrap());
+        assert!(getsockopt(&listener, sockopt::ReusePort).unwrap());
+        let address = listener.local_addr().unwrap();
+        TcpStream::connect(address).unwrap();
+        listener.accept().unwrap();
+
+        let udp: ListenerSpec = "udp:127.0.0.1:0".parse().unwrap();
+        let socket = UdpSocket::from(udp.open().unwrap());
+        assert!(socket.local_addr().unwrap().port() != 0);
+    }
+
+    #[test]
+    fn open_unix_and_fifo() {
+        setup();
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("app.sock");
+        let fifo_path = dir.path().join("ctl");
+
+        // A stale socket should be replaced.
+        drop(UnixListener::bind(&socket_path).unwrap());
+
+        let listeners = open_listeners(
+            [
+                format!("app=unix:{},mode=0600", socket_path.display()),
+                format!("ctl=fifo:{},mode=0640", fifo_path.display()),
+            ],
+            3,
+        )
+        .unwrap();
+        assert_eq!(listeners[0].name.as_deref(), Some("app"));
+        assert_eq!(listeners[1].mapping.child_fd, 4);
+        assert_eq!(
+            metadata(&socket_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let fifo_metadata = metadata(&fifo_path).unwrap();
+        assert!(fifo_metadata.file_type().is_fifo());
+        assert_eq!(fifo_metadata.permissions().mode() & 0o777, 0o640);
+
+        UnixStream::connect(&socket_path).unwrap();
+        let [socket, fifo] = listeners.try_into().unwrap();
+        UnixListener::from(socket.mapping.parent_fd)
+            .accept()
+            .unwrap();
+        let mut fifo = std::fs::File::from(fifo.mapping.parent_fd);
+        fifo.write_all(b"hi").unwrap();
+        let mut buf = [0; 2];
+        fifo.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        let flags = OFlag::from_bits_retain(fcntl(&fifo, F_GETFL).unwrap());
+        assert!(!flags.contains(OFlag::O_NONBLOCK));
+    }
+
+    #[test]
+    fn unix_socket_in_use() {
+        setup();
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("app.sock");
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+
+        let spec = ListenerSpec::new(ListenAddress::Unix(socket_path.clone()));
+        match spec.open() {
+            Err(ListenError::Io { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+            }
+            result => panic!("Unexpected result {result:?}"),
+        }
+        // The socket in use shouldn't have been removed.
+        UnixStream::connect(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn open_abstract() {
+        setup();
+        let name = format!("command-fds-test-{}", std::process::id());
+        let spec = ListenerSpec::new(ListenAddress::UnixAbstract(name.clone()));
+        let fd = spec.open().unwrap();
+        let address: UnixAddr = getsockname(fd.as_raw_fd()).unwrap();
+        assert_eq!(address.as_abstract(), Some(name.as_bytes()));
+    }
+
+    #[test]
+    fn map_to_child() {
+        setup();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"[ "$COMMAND_FDS_NAMES" = "3=one:4=two" ] && [ -S /proc/self/fd/3 ] && [ -S /proc/self/fd/4 ]"#,
+        );
+        map_listeners(
+            &mut command,
+            ["one=tcp:127.0.0.1:0", "two=udp:127.0.0.1:0"],
+            3,
+        )
+        .unwrap();
+        assert!(command.status().unwrap().success());
+    }
+}
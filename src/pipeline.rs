@@ -0,0 +1,344 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connecting several commands with pipes between arbitrary file descriptors.
+//!
+//! Unlike a shell pipeline, which only connects each command's stdout to the next command's stdin,
+//! a [`Pipeline`] can connect any file descriptor of one command to any file descriptor of another.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::pipeline::Pipeline;
+//! use std::process::Command;
+//!
+//! let mut pipeline = Pipeline::new();
+//! let producer = pipeline.command(Command::new("producer"));
+//! let consumer = pipeline.command(Command::new("consumer"));
+//! let logger = pipeline.command(Command::new("logger"));
+//! // Connect the producer's FD 3 to the consumer's FD 4, and its stderr to the logger's stdin.
+//! pipeline
+//!     .connect((producer, 3), (consumer, 4))
+//!     .connect((producer, 2), (logger, 0));
+//! let status = pipeline.spawn().unwrap().wait().unwrap();
+//! assert!(status.success());
+//! ```
+
+use crate::{CommandFdExt, FdMapping};
+use nix::{fcntl::OFlag, unistd::pipe2};
+use std::{
+    collections::BTreeMap,
+    io,
+    os::fd::RawFd,
+    process::{Child, Command, ExitStatus},
+};
+use thiserror::Error;
+
+/// A file descriptor of one of the commands in a [`Pipeline`], identified by the index of the
+/// command as returned by [`Pipeline::command`] and the file descriptor number in that command.
+pub type Endpoint = (usize, RawFd);
+
+/// Errors that can occur while spawning a pipeline.
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    /// I/O error creating pipes or spawning a command
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A connection refers to a command which isn't in the pipeline
+    #[error("No command with index {0} in pipeline")]
+    InvalidCommand(usize),
+
+    /// The same file descriptor is connected as the writer to more than one reader
+    #[error("FD {1} of command {0} is connected to more than one reader")]
+    FanOut(usize, RawFd),
+
+    /// The same file descriptor of a command is used by more than one connection
+    #[error("FD {1} of command {0} is used by more than one connection")]
+    Collision(usize, RawFd),
+}
+
+/// A builder for a set of commands connected by pipes.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    commands: Vec<Command>,
+    connections: Vec<(Endpoint, Endpoint)>,
+}
+
+impl Pipeline {
+    /// Creates a new empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a command to the pipeline, and returns its index for use in connections.
+    ///
+    /// The command shouldn't have any other file descriptor mappings applied, as they would
+    /// conflict with those applied by the pipeline.
+    pub fn command(&mut self, command: Command) -> usize {
+        self.commands.push(command);
+        self.commands.len() - 1
+    }
+
+    /// Returns a mutable reference to the command with the given index, e.g. to configure its
+    /// stdio.
+    pub fn command_mut(&mut self, index: usize) -> Option<&mut Command> {
+        self.commands.get_mut(index)
+    }
+
+    /// Connects `writer` to `reader` with a pipe, so that whatever the writer command writes to the
+    /// given file descriptor can be read by the reader command from its given file descriptor.
+    ///
+    /// Several writers may be connected to the same reader, in which case they share a single
+    /// pipe. A writer may not be connected to more than one reader.
+    pub fn connect(&mut self, writer: Endpoint, reader: Endpoint) -> &mut Self {
+        self.connections.push((writer, reader));
+        self
+    }
+
+    /// Creates the pipes, and spawns all the commands with their ends mapped.
+    ///
+    /// The parent's copies of the pipes are closed once all the commands have been spawned. If
+    /// spawning any command fails then the commands already spawned are killed.
+    pub fn spawn(self) -> Result<RunningPipeline, PipelineError> {
+        let mut mappings = self.mappings()?;
+        let mut children: Vec<Child> = Vec::with_capacity(self.commands.len());
+        for (index, mut command) in self.commands.into_iter().enumerate() {
+            let command_mappings = mappings.remove(&index).unwrap_or_default();
+            let result = command
+                .fd_mappings(command_mappings)
+                .map_err(io::Error::other)
+                .and_then(|command| command.spawn());
+            // Drop the command, and so the parent's copies of its pipe ends.
+            drop(command);
+            match result {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    for mut child in children {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(RunningPipeline { children })
+    }
+
+    /// Validates the connections and creates pipes for them, returning the mappings for each
+    /// command.
+    fn mappings(&self) -> Result<BTreeMap<usize, Vec<FdMapping>>, PipelineError> {
+        let mut roles: BTreeMap<Endpoint, Role> = BTreeMap::new();
+        for &(writer, reader) in &self.connections {
+            for endpoint in [writer, reader] {
+                if endpoint.0 >= self.commands.len() {
+                    return Err(PipelineError::InvalidCommand(endpoint.0));
+                }
+            }
+            match roles.get(&writer) {
+                None => {
+                    roles.insert(writer, Role::Writer(reader));
+                }
+                Some(Role::Writer(existing)) if *existing == reader => {}
+                Some(Role::Writer(_)) => return Err(PipelineError::FanOut(writer.0, writer.1)),
+                Some(Role::Reader) => return Err(PipelineError::Collision(writer.0, writer.1)),
+            }
+            match roles.get(&reader) {
+                None => {
+                    roles.insert(reader, Role::Reader);
+                }
+                Some(Role::Reader) => {}
+                Some(Role::Writer(_)) => {
+                    return Err(PipelineError::Collision(reader.0, reader.1));
+                }
+            }
+        }
+
+        let mut writers_by_reader: BTreeMap<Endpoint, Vec<Endpoint>> = BTreeMap::new();
+        for (&endpoint, role) in &roles {
+            if let Role::Writer(reader) = role {
+                writers_by_reader.entry(*reader).or_default().push(endpoint);
+            }
+        }
+
+        let mut mappings: BTreeMap<usize, Vec<FdMapping>> = BTreeMap::new();
+        for (reader, writers) in writers_by_reader {
+            let (read, write) = pipe2(OFlag::O_CLOEXEC).map_err(io::Error::from)?;
+            for (command, child_fd) in writers {
+                mappings.entry(command).or_default().push(FdMapping {
+                    parent_fd: write.try_clone()?,
+                    child_fd,
+                });
+            }
+            mappings.entry(reader.0).or_default().push(FdMapping {
+                parent_fd: read,
+                child_fd: reader.1,
+            });
+        }
+        Ok(mappings)
+    }
+}
+
+/// The role of an endpoint in a pipeline's connections.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Role {
+    /// The endpoint writes to the given reader.
+    Writer(Endpoint),
+    /// The endpoint reads from one or more writers.
+    Reader,
+}
+
+/// A set of running commands spawned by [`Pipeline::spawn`].
+#[derive(Debug)]
+pub struct RunningPipeline {
+    children: Vec<Child>,
+}
+
+impl RunningPipeline {
+    /// Returns the child processes, in the same order as the commands were added to the pipeline.
+    pub fn children(&self) -> &[Child] {
+        &self.children
+    }
+
+    /// Returns the child processes mutably, e.g. to take their stdio pipes.
+    pub fn children_mut(&mut self) -> &mut [Child] {
+        &mut self.children
+    }
+
+    /// Waits for all the child processes to exit, and returns their exit statuses.
+    pub fn wait(&mut self) -> io::Result<PipelineStatus> {
+        let statuses = self
+            .children
+            .iter_mut()
+            .map(Child::wait)
+            .collect::<io::Result<_>>()?;
+        Ok(PipelineStatus { statuses })
+    }
+}
+
+/// The exit statuses of all commands in a pipeline.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PipelineStatus {
+    /// The exit statuses, in the same order as the commands were added to the pipeline.
+    pub statuses: Vec<ExitStatus>,
+}
+
+impl PipelineStatus {
+    /// Returns whether all commands exited successfully.
+    pub fn success(&self) -> bool {
+        self.statuses.iter().all(ExitStatus::success)
+    }
+
+    /// Returns the index and status of the first command which didn't exit successfully, if any.
+    pub fn first_failure(&self) -> Option<(usize, ExitStatus)> {
+        self.statuses
+            .iter()
+            .copied()
+            .enumerate()
+            .find(|(_, status)| !status.success())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{io::Read, process::Stdio};
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    fn read_stdout(child: &mut Child) -> String {
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn connect_arbitrary_fds() {
+        setup();
+        let mut pipeline = Pipeline::new();
+        let a = pipeline.command(sh("echo data >&3; echo error >&2"));
+        let b = pipeline.command(sh("cat <&4"));
+        let c = pipeline.command(sh("sed s/^/log:/"));
+        pipeline.command_mut(b).unwrap().stdout(Stdio::piped());
+        pipeline.command_mut(c).unwrap().stdout(Stdio::piped());
+        pipeline.connect((a, 3), (b, 4)).connect((a, 2), (c, 0));
+
+        let mut running = pipeline.spawn().unwrap();
+        assert_eq!(read_stdout(&mut running.children_mut()[b]), "data\n");
+        assert_eq!(read_stdout(&mut running.children_mut()[c]), "log:error\n");
+        let status = running.wait().unwrap();
+        assert!(status.success());
+        assert_eq!(status.statuses.len(), 3);
+    }
+
+    #[test]
+    fn several_writers() {
+        setup();
+        let mut pipeline = Pipeline::new();
+        let a = pipeline.command(sh("echo a"));
+        let b = pipeline.command(sh("echo b; exit 3"));
+        let sink = pipeline.command(sh("sort"));
+        pipeline.command_mut(sink).unwrap().stdout(Stdio::piped());
+        pipeline
+            .connect((a, 1), (sink, 0))
+            .connect((b, 1), (sink, 0));
+
+        let mut running = pipeline.spawn().unwrap();
+        assert_eq!(read_stdout(&mut running.children_mut()[sink]), "a\nb\n");
+        let status = running.wait().unwrap();
+        assert!(!status.success());
+        assert_eq!(status.first_failure().unwrap().0, b);
+        assert_eq!(status.first_failure().unwrap().1.code(), Some(3));
+    }
+
+    #[test]
+    fn invalid_connections() {
+        setup();
+        let pipeline = || {
+            let mut pipeline = Pipeline::new();
+            pipeline.command(sh("true"));
+            pipeline.command(sh("true"));
+            pipeline.command(sh("true"));
+            pipeline
+        };
+
+        let mut invalid = pipeline();
+        invalid.connect((0, 1), (3, 0));
+        assert!(matches!(
+            invalid.spawn(),
+            Err(PipelineError::InvalidCommand(3))
+        ));
+
+        let mut fan_out = pipeline();
+        fan_out.connect((0, 1), (1, 0)).connect((0, 1), (2, 0));
+        assert!(matches!(fan_out.spawn(), Err(PipelineError::FanOut(0, 1))));
+
+        let mut collision = pipeline();
+        collision.connect((0, 1), (1, 0)).connect((2, 1), (0, 1));
+        assert!(matches!(
+            collision.spawn(),
+            Err(PipelineError::Collision(0, 1))
+        ));
+    }
+}
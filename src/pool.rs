@@ -0,0 +1,660 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pool of identical worker processes which all share the same listening sockets, as used by
+//! prefork servers.
+//!
+//! The pool keeps the listening sockets open in the parent process, and passes duplicates of them
+//! to each worker it spawns. Workers which exit are restarted with exponential backoff, and the
+//! pool can be rolled over to a new command one worker at a time without closing the listeners.
+//!
+//! The pool doesn't run any threads or tasks of its own; instead [`WorkerPool::maintain`] must be
+//! called periodically to reap exited workers and start new ones.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::pool::WorkerPool;
+//! use command_fds::{FdMapping, NamedFdMapping};
+//! use std::net::TcpListener;
+//! use std::process::{Child, Command};
+//! use std::thread::sleep;
+//! use std::time::Duration;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! let listeners = vec![NamedFdMapping {
+//!     name: Some("http".to_owned()),
+//!     mapping: FdMapping {
+//!         parent_fd: listener.into(),
+//!         child_fd: 3,
+//!     },
+//! }];
+//!
+//! let mut pool = WorkerPool::<Child>::new(4, listeners, || Command::new("worker")).unwrap();
+//! pool.start().unwrap();
+//! loop {
+//!     for event in pool.maintain().unwrap() {
+//!         println!("{event:?}");
+//!     }
+//!     sleep(Duration::from_millis(100));
+//! }
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, NamedFdMapping,
+    inherited::{FD_NAMES_ENV_VAR, format_fd_names, is_valid_fd_name},
+};
+use nix::{
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
+use std::{
+    fmt::{self, Debug, Formatter},
+    io,
+    process::{Child, Command, ExitStatus},
+    time::{Duration, Instant},
+};
+
+/// A child process which can be managed by a [`WorkerPool`].
+pub trait WorkerProcess: Sized {
+    /// Spawns the given command.
+    fn spawn(command: Command) -> io::Result<Self>;
+
+    /// Returns the process ID of the child, if it is still known.
+    fn pid(&self) -> Option<u32>;
+
+    /// Checks whether the child has exited, without blocking.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+
+    /// Asks the child to exit by sending it `SIGTERM`.
+    fn terminate(&mut self) -> io::Result<()> {
+        if let Some(pid) = self.pid() {
+            kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+        }
+        Ok(())
+    }
+}
+
+impl WorkerProcess for Child {
+    fn spawn(mut command: Command) -> io::Result<Self> {
+        command.spawn()
+    }
+
+    fn pid(&self) -> Option<u32> {
+        Some(self.id())
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl WorkerProcess for tokio::process::Child {
+    fn spawn(command: Command) -> io::Result<Self> {
+        tokio::process::Command::from(command).spawn()
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.id()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        tokio::process::Child::try_wait(self)
+    }
+}
+
+/// Parameters for the exponential backoff applied when restarting workers which exit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Backoff {
+    /// The delay before restarting a worker which exits for the first time.
+    pub initial: Duration,
+    /// The maximum delay before restarting a worker. Once a worker has run for at least this long,
+    /// the delay is reset to `initial` the next time it exits.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay to use after the given number of consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        self.initial
+            .checked_mul(1 << failures.min(31))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// Something which happened to a worker in a [`WorkerPool`].
+#[derive(Debug)]
+pub enum WorkerEvent {
+    /// A worker exited.
+    Exited {
+        /// The index of the worker's slot in the pool.
+        slot: usize,
+        /// The process ID of the worker, if known.
+        pid: Option<u32>,
+        /// The worker's exit status.
+        status: ExitStatus,
+        /// Whether the worker was asked to exit because the pool was rolled over to a new command
+        /// or shut down. Otherwise it will be restarted after a backoff delay.
+        retired: bool,
+    },
+    /// Spawning a worker failed. It will be retried after a backoff delay.
+    SpawnFailed {
+        /// The index of the worker's slot in the pool.
+        slot: usize,
+        /// The error spawning the worker.
+        error: io::Error,
+    },
+}
+
+/// A running worker process.
+#[derive(Debug)]
+struct Running<C> {
+    process: C,
+    pid: Option<u32>,
+    started: Instant,
+    generation: u64,
+}
+
+/// A slot in the pool, which normally has a worker running.
+#[derive(Debug)]
+struct Slot<C> {
+    worker: Option<Running<C>>,
+    failures: u32,
+    restart_at: Option<Instant>,
+    last_status: Option<ExitStatus>,
+}
+
+/// A pool of identical worker processes which all share the same listening sockets.
+///
+/// The type parameter is the type of child process, either [`std::process::Child`] or
+/// `tokio::process::Child` if the `tokio` feature is enabled.
+pub struct WorkerPool<C: WorkerProcess> {
+    listeners: Vec<NamedFdMapping>,
+    make_command: Box<dyn FnMut() -> Command + Send>,
+    generation: u64,
+    slots: Vec<Slot<C>>,
+    retiring: Vec<(usize, Running<C>)>,
+    backoff: Backoff,
+    shutting_down: bool,
+}
+
+impl<C: WorkerProcess + Debug> Debug for WorkerPool<C> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("listeners", &self.listeners)
+            .field("generation", &self.generation)
+            .field("slots", &self.slots)
+            .field("retiring", &self.retiring)
+            .field("backoff", &self.backoff)
+            .field("shutting_down", &self.shutting_down)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: WorkerProcess> WorkerPool<C> {
+    /// Creates a new pool of `size` workers, each of which will be passed duplicates of the given
+    /// listeners, and run with a command returned by `make_command`.
+    ///
+    /// The names of the listeners are advertised to the workers in the [`FD_NAMES_ENV_VAR`]
+    /// environment variable, so they must not be empty or contain `:`; otherwise an error of kind
+    /// [`io::ErrorKind::InvalidInput`] is returned. No workers are started until
+    /// [`start`](Self::start) is called.
+    pub fn new(
+        size: usize,
+        listeners: Vec<NamedFdMapping>,
+        make_command: impl FnMut() -> Command + Send + 'static,
+    ) -> io::Result<Self> {
+        if let Some(name) = listeners
+            .iter()
+            .filter_map(|listener| listener.name.as_deref())
+            .find(|name| !is_valid_fd_name(name))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid listener name {name:?}"),
+            ));
+        }
+        Ok(Self {
+            listeners,
+            make_command: Box::new(make_command),
+            generation: 0,
+            slots: (0..size)
+                .map(|_| Slot {
+                    worker: None,
+                    failures: 0,
+                    restart_at: None,
+                    last_status: None,
+                })
+                .collect(),
+            retiring: Vec::new(),
+            backoff: Backoff::default(),
+            shutting_down: false,
+        })
+    }
+
+    /// Sets the backoff parameters for restarting workers.
+    pub fn backoff(&mut self, backoff: Backoff) -> &mut Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Starts all workers which aren't already running.
+    ///
+    /// If spawning any worker fails then the error is returned, and the remaining workers are not
+    /// started until [`maintain`](Self::maintain) is called. The worker which failed is retried
+    /// after a backoff delay.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.shutting_down = false;
+        for index in 0..self.slots.len() {
+            if self.slots[index].worker.is_none() {
+                match self.spawn_worker() {
+                    Ok(worker) => {
+                        let slot = &mut self.slots[index];
+                        slot.worker = Some(worker);
+                        slot.restart_at = None;
+                    }
+                    Err(error) => {
+                        let now = Instant::now();
+                        for slot in &mut self.slots[index + 1..] {
+                            if slot.worker.is_none() {
+                                slot.restart_at.get_or_insert(now);
+                            }
+                        }
+                        let slot = &mut self.slots[index];
+                        slot.restart_at = Some(now + self.backoff.delay(slot.failures));
+                        slot.failures += 1;
+                        return Err(error);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the process IDs of the workers in each slot of the pool, or `None` for slots which
+    /// don't currently have a worker running.
+    pub fn pids(&self) -> Vec<Option<u32>> {
+        self.slots
+            .iter()
+            .map(|slot| slot.worker.as_ref().and_then(|worker| worker.pid))
+            .collect()
+    }
+
+    /// Returns the exit status of the last worker to exit from each slot of the pool.
+    pub fn last_statuses(&self) -> Vec<Option<ExitStatus>> {
+        self.slots.iter().map(|slot| slot.last_status).collect()
+    }
+
+    /// Returns the number of worker processes currently running, including any which are being
+    /// retired.
+    pub fn running(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.worker.is_some())
+            .count()
+            + self.retiring.len()
+    }
+
+    /// Returns the earliest time at which a worker is due to be restarted, if any.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.slots.iter().filter_map(|slot| slot.restart_at).min()
+    }
+
+    /// Rolls the pool over to run a new command, keeping the listeners open.
+    ///
+    /// Workers are replaced one at a time by subsequent calls to [`maintain`](Self::maintain): a
+    /// new worker is started, and then the old one is sent `SIGTERM`. The next worker isn't
+    /// replaced until the old one has exited.
+    pub fn roll(&mut self, make_command: impl FnMut() -> Command + Send + 'static) {
+        self.make_command = Box::new(make_command);
+        self.generation += 1;
+    }
+
+    /// Returns whether there are still workers running an old command after a call to
+    /// [`roll`](Self::roll).
+    pub fn is_rolling(&self) -> bool {
+        !self.retiring.is_empty()
+            || self.slots.iter().any(|slot| {
+                slot.worker
+                    .as_ref()
+                    .is_some_and(|worker| worker.generation != self.generation)
+            })
+    }
+
+    /// Sends `SIGTERM` to all workers, and stops restarting them.
+    ///
+    /// [`maintain`](Self::maintain) should still be called until [`running`](Self::running)
+    /// returns 0, to reap the workers as they exit.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.shutting_down = true;
+        for slot in &mut self.slots {
+            slot.restart_at = None;
+            if let Some(worker) = &mut slot.worker {
+                worker.process.terminate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reaps any workers which have exited, restarts any which are due to be restarted, and
+    /// continues rolling the pool over to a new command if necessary.
+    ///
+    /// This doesn't block, and returns the events which happened.
+    pub fn maintain(&mut self) -> io::Result<Vec<WorkerEvent>> {
+        let mut events = Vec::new();
+        let now = Instant::now();
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let Some(worker) = &mut slot.worker else {
+                continue;
+            };
+            let Some(status) = worker.process.try_wait()? else {
+                continue;
+            };
+            if now.duration_since(worker.started) >= self.backoff.max {
+                slot.failures = 0;
+            }
+            if !self.shutting_down {
+                slot.restart_at = Some(now + self.backoff.delay(slot.failures));
+                slot.failures += 1;
+            }
+            slot.last_status = Some(status);
+            events.push(WorkerEvent::Exited {
+                slot: index,
+                pid: worker.pid,
+                status,
+                retired: self.shutting_down,
+            });
+            slot.worker = None;
+        }
+
+        let mut still_retiring = Vec::new();
+        for (index, mut worker) in self.retiring.drain(..) {
+            match worker.process.try_wait()? {
+                Some(status) => events.push(WorkerEvent::Exited {
+                    slot: index,
+                    pid: worker.pid,
+                    status,
+                    retired: true,
+                }),
+                None => still_retiring.push((index, worker)),
+            }
+        }
+        self.retiring = still_retiring;
+
+        if self.shutting_down {
+            return Ok(events);
+        }
+
+        for index in 0..self.slots.len() {
+            if self.slots[index]
+                .restart_at
+                .is_some_and(|restart_at| restart_at <= now)
+            {
+                match self.spawn_worker() {
+                    Ok(worker) => {
+                        let slot = &mut self.slots[index];
+                        slot.worker = Some(worker);
+                        slot.restart_at = None;
+                    }
+                    Err(error) => {
+                        let slot = &mut self.slots[index];
+                        slot.restart_at = Some(now + self.backoff.delay(slot.failures));
+                        slot.failures += 1;
+                        events.push(WorkerEvent::SpawnFailed { slot: index, error });
+                    }
+                }
+            }
+        }
+
+        if self.retiring.is_empty()
+            && let Some(index) = self.slots.iter().position(|slot| {
+                slot.worker
+                    .as_ref()
+                    .is_some_and(|worker| worker.generation != self.generation)
+            })
+        {
+            match self.spawn_worker() {
+                Ok(worker) => {
+                    let mut old = self.slots[index].worker.replace(worker).unwrap();
+                    old.process.terminate()?;
+                    self.retiring.push((index, old));
+                }
+                Err(error) => events.push(WorkerEvent::SpawnFailed { slot: index, error }),
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Spawns a new worker with the current command and duplicates of the listeners.
+    fn spawn_worker(&mut self) -> io::Result<Running<C>> {
+        let mut command = (self.make_command)();
+        let mappings = self
+            .listeners
+            .iter()
+            .map(|listener| {
+                Ok(FdMapping {
+                    parent_fd: listener.mapping.parent_fd.try_clone()?,
+                    child_fd: listener.mapping.child_fd,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        command
+            .env(FD_NAMES_ENV_VAR, format_fd_names(&self.listeners))
+            .fd_mappings(mappings)
+            .map_err(io::Error::other)?;
+        let process = C::spawn(command)?;
+        Ok(Running {
+            pid: process.pid(),
+            process,
+            started: Instant::now(),
+            generation: self.generation,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::{net::TcpListener, os::unix::process::ExitStatusExt, thread::sleep};
+
+    fn sh_command(script: &'static str) -> impl FnMut() -> Command + Send + 'static {
+        move || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        }
+    }
+
+    fn listeners() -> Vec<NamedFdMapping> {
+        vec![NamedFdMapping {
+            name: Some("http".to_owned()),
+            mapping: FdMapping {
+                parent_fd: TcpListener::bind("127.0.0.1:0").unwrap().into(),
+                child_fd: 3,
+            },
+        }]
+    }
+
+    /// Calls `maintain` until the given condition is true, and returns all events.
+    fn maintain_until(
+        pool: &mut WorkerPool<Child>,
+        condition: impl Fn(&WorkerPool<Child>, &[WorkerEvent]) -> bool,
+    ) -> Vec<WorkerEvent> {
+        let mut events = Vec::new();
+        for _ in 0..500 {
+            events.extend(pool.maintain().unwrap());
+            if condition(pool, &events) {
+                return events;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("Condition not reached, events: {events:?}");
+    }
+
+    #[test]
+    fn restart_with_backoff() {
+        setup();
+        let mut pool = WorkerPool::<Child>::new(
+            2,
+            listeners(),
+            sh_command(r#"[ "$COMMAND_FDS_NAMES" = 3=http ] && [ -S /proc/self/fd/3 ] && exit 3"#),
+        )
+        .unwrap();
+        pool.backoff(Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(10),
+        });
+        pool.start().unwrap();
+        assert_eq!(pool.running(), 2);
+
+        let events = maintain_until(&mut pool, |_, events| events.len() >= 6);
+        for event in events {
+            let WorkerEvent::Exited {
+                status, retired, ..
+            } = event
+            else {
+                panic!("Unexpected event {event:?}");
+            };
+            assert_eq!(status.code(), Some(3));
+            assert!(!retired);
+        }
+        assert_eq!(pool.last_statuses()[0].unwrap().code(), Some(3));
+        assert!(pool.slots[0].failures >= 3);
+    }
+
+    #[test]
+    fn roll_and_shutdown() {
+        setup();
+        let mut pool =
+            WorkerPool::<Child>::new(2, listeners(), sh_command("exec sleep 10")).unwrap();
+        pool.start().unwrap();
+        let old_pids = pool.pids();
+
+        pool.roll(sh_command("exec sleep 10"));
+        assert!(pool.is_rolling());
+        let events = maintain_until(&mut pool, |pool, _| !pool.is_rolling());
+
+        assert_eq!(events.len(), 2);
+        for event in events {
+            let WorkerEvent::Exited {
+                pid,
+                status,
+                retired,
+                ..
+            } = event
+            else {
+                panic!("Unexpected event {event:?}");
+            };
+            assert!(old_pids.contains(&pid));
+            assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+            assert!(retired);
+        }
+        let new_pids = pool.pids();
+        assert!(
+            new_pids
+                .iter()
+                .all(|pid| pid.is_some() && !old_pids.contains(pid))
+        );
+
+        pool.shutdown().unwrap();
+        let events = maintain_until(&mut pool, |pool, _| pool.running() == 0);
+        assert_eq!(events.len(), 2);
+        assert_eq!(pool.pids(), vec![None, None]);
+        assert_eq!(pool.next_restart(), None);
+    }
+
+    #[test]
+    fn spawn_failure() {
+        setup();
+        let mut pool = WorkerPool::<Child>::new(2, Vec::new(), || {
+            Command::new("/nonexistent/command-fds-test")
+        })
+        .unwrap();
+        pool.backoff(Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+        });
+        let before = Instant::now();
+        assert!(pool.start().is_err());
+        assert_eq!(pool.running(), 0);
+        // The failed worker is retried after the initial backoff, and the other one as soon as
+        // possible.
+        assert_eq!(pool.slots[0].failures, 1);
+        assert!(pool.slots[0].restart_at.unwrap() >= before + Duration::from_millis(100));
+        assert!(pool.slots[1].restart_at.unwrap() <= Instant::now());
+
+        sleep(Duration::from_millis(100));
+        let now = Instant::now();
+        let events = pool.maintain().unwrap();
+        assert_eq!(events.len(), 2);
+        for (slot, event) in events.iter().enumerate() {
+            let WorkerEvent::SpawnFailed {
+                slot: failed_slot,
+                error,
+            } = event
+            else {
+                panic!("Unexpected event {event:?}");
+            };
+            assert_eq!(*failed_slot, slot);
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+        // The backoff doubles with each consecutive failure.
+        assert_eq!(pool.slots[0].failures, 2);
+        assert!(pool.slots[0].restart_at.unwrap() >= now + Duration::from_millis(200));
+        assert_eq!(pool.slots[1].failures, 1);
+        assert!(pool.slots[1].restart_at.unwrap() >= now + Duration::from_millis(100));
+        // Nothing is retried until the backoff delay has passed.
+        assert!(pool.maintain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_listener_name() {
+        setup();
+        let mut listeners = listeners();
+        listeners[0].name = Some("a:b".to_owned());
+        let error = WorkerPool::<Child>::new(1, listeners, || Command::new("true")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[::tokio::test(crate = "::tokio")]
+    async fn tokio_workers() {
+        setup();
+        let mut pool =
+            WorkerPool::<tokio::process::Child>::new(2, listeners(), sh_command("exit 0")).unwrap();
+        pool.start().unwrap();
+        assert!(pool.pids().iter().all(Option::is_some));
+
+        let mut exits = 0;
+        while exits < 2 {
+            exits += pool.maintain().unwrap().len();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        pool.shutdown().unwrap();
+    }
+}
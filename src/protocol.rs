@@ -0,0 +1,567 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocols for advertising passed file descriptors to a child process.
+//!
+//! Different runtimes expect to learn about the file descriptors they inherit in different ways.
+//! An [`FdProtocol`] describes both how the parent advertises a set of named mappings, and how the
+//! child parses the advertisement with
+//! [`InheritedFds::apply_protocol`](crate::inherited::InheritedFds::apply_protocol). Built-in
+//! implementations are provided for:
+//!
+//! - [`CommandFds`], this crate's own `COMMAND_FDS_NAMES` variable;
+//! - [`Systemd`], the systemd socket activation protocol also used by `systemfd` and `listenfd`;
+//! - [`Einhorn`], the `EINHORN_FDS` variables used by Einhorn;
+//! - [`Sequential`], consecutive file descriptors with no advertisement, as used by Go's
+//!   `ExtraFiles`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::protocol::{FdProtocol, Systemd};
+//! use command_fds::{FdMapping, NamedFdMapping};
+//! use std::net::TcpListener;
+//! use std::process::Command;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! let mut command = Command::new("server");
+//! Systemd::default()
+//!     .advertise(vec![NamedFdMapping {
+//!         name: Some("http".to_owned()),
+//!         mapping: FdMapping {
+//!             parent_fd: listener.into(),
+//!             child_fd: 3,
+//!         },
+//!     }])
+//!     .unwrap()
+//!     .apply(&mut command)
+//!     .unwrap();
+//! command.spawn().unwrap();
+//! ```
+
+use crate::{
+    CommandFdExt, FdMapping, FdMappingCollision, NamedFdMapping,
+    inherited::{
+        FD_NAMES_ENV_VAR, InheritedFdError, InheritedFds, format_fd_names, is_valid_fd_name,
+        parse_fd_names,
+    },
+};
+use std::{os::fd::RawFd, process::Command};
+use thiserror::Error;
+
+/// The first file descriptor passed by the systemd socket activation protocol.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The maximum length of a name in the systemd socket activation protocol.
+const SD_MAX_NAME_LENGTH: usize = 255;
+
+/// Errors that can occur while advertising file descriptors.
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ProtocolError {
+    /// The name can't be represented by the protocol
+    #[error("Invalid FD name {0:?}")]
+    InvalidName(String),
+}
+
+/// The file descriptor mappings, environment variables and arguments needed to pass a set of file
+/// descriptors to a child process according to some protocol.
+#[derive(Debug, Default)]
+pub struct Advertisement {
+    /// The file descriptor mappings to apply.
+    pub mappings: Vec<FdMapping>,
+    /// The environment variables to set.
+    pub env: Vec<(String, String)>,
+    /// The environment variables to remove, if they were inherited from the parent.
+    pub env_remove: Vec<String>,
+    /// The arguments to append to the command.
+    pub args: Vec<String>,
+}
+
+impl Advertisement {
+    /// Adds the mappings, environment variables and arguments to the given command.
+    ///
+    /// As with [`CommandFdExt::fd_mappings`], this shouldn't be combined with other mappings on the
+    /// same command.
+    pub fn apply(self, command: &mut Command) -> Result<&mut Command, FdMappingCollision> {
+        for var in self.env_remove {
+            command.env_remove(var);
+        }
+        command
+            .envs(self.env)
+            .args(self.args)
+            .fd_mappings(self.mappings)
+    }
+}
+
+/// A protocol for advertising passed file descriptors to a child process.
+pub trait FdProtocol {
+    /// Returns how to pass the given file descriptors to a child process.
+    ///
+    /// Depending on the protocol, the `child_fd` of the mappings may be changed.
+    fn advertise(&self, fds: Vec<NamedFdMapping>) -> Result<Advertisement, ProtocolError>;
+
+    /// Parses the advertisement in the child process, returning the advertised file descriptors
+    /// in order along with their names, if any.
+    ///
+    /// `env` looks up the value of an environment variable, and `fds` is the registry of inherited
+    /// file descriptors.
+    fn parse(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+        fds: &InheritedFds,
+    ) -> Result<Vec<(RawFd, Option<String>)>, InheritedFdError>;
+}
+
+/// This crate's own protocol, which keeps the given file descriptor numbers and advertises their
+/// names in the [`FD_NAMES_ENV_VAR`] environment variable.
+///
+/// Unnamed file descriptors are passed but not advertised.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CommandFds;
+
+impl FdProtocol for CommandFds {
+    fn advertise(&self, fds: Vec<NamedFdMapping>) -> Result<Advertisement, ProtocolError> {
+        if let Some(name) = fds
+            .iter()
+            .filter_map(|fd| fd.name.as_ref())
+            .find(|name| !is_valid_fd_name(name))
+        {
+            return Err(ProtocolError::InvalidName(name.clone()));
+        }
+        Ok(Advertisement {
+            env: vec![(FD_NAMES_ENV_VAR.to_owned(), format_fd_names(&fds))],
+            mappings: fds.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn parse(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+        _fds: &InheritedFds,
+    ) -> Result<Vec<(RawFd, Option<String>)>, InheritedFdError> {
+        let Some(value) = env(FD_NAMES_ENV_VAR) else {
+            return Ok(Vec::new());
+        };
+        Ok(parse_fd_names(&value)?
+            .into_iter()
+            .map(|(raw_fd, name)| (raw_fd, Some(name)))
+            .collect())
+    }
+}
+
+/// The systemd socket activation protocol, which passes file descriptors consecutively from
+/// [`SD_LISTEN_FDS_START`], with `LISTEN_FDS`, `LISTEN_FDNAMES` and `LISTEN_PID` environment
+/// variables.
+///
+/// Unnamed file descriptors are advertised with the name `unknown`, as systemd does.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Systemd {
+    /// The value to set for `LISTEN_PID`.
+    ///
+    /// The PID of a child process isn't known until after it is spawned, so this can only be set
+    /// when the command will be executed in place of the current process, or in a process which
+    /// has already been forked. If it is `None` then `LISTEN_PID` is removed from the environment
+    /// instead. This is accepted by `listenfd` and similar libraries, but `sd_listen_fds` in
+    /// libsystemd requires `LISTEN_PID` to be set.
+    pub listen_pid: Option<u32>,
+}
+
+impl FdProtocol for Systemd {
+    fn advertise(&self, fds: Vec<NamedFdMapping>) -> Result<Advertisement, ProtocolError> {
+        let mut names = Vec::new();
+        let mut mappings = Vec::new();
+        for (fd, child_fd) in fds.into_iter().zip(SD_LISTEN_FDS_START..) {
+            let name = fd.name.unwrap_or_else(|| "unknown".to_owned());
+            if !is_valid_fd_name(&name)
+                || name.len() > SD_MAX_NAME_LENGTH
+                || name.chars().any(|c| c.is_control())
+            {
+                return Err(ProtocolError::InvalidName(name));
+            }
+            names.push(name);
+            mappings.push(FdMapping {
+                parent_fd: fd.mapping.parent_fd,
+                child_fd,
+            });
+        }
+        let mut env = vec![
+            ("LISTEN_FDS".to_owned(), mappings.len().to_string()),
+            ("LISTEN_FDNAMES".to_owned(), names.join(":")),
+        ];
+        let mut env_remove = Vec::new();
+        match self.listen_pid {
+            Some(pid) => env.push(("LISTEN_PID".to_owned(), pid.to_string())),
+            None => env_remove.push("LISTEN_PID".to_owned()),
+        }
+        Ok(Advertisement {
+            mappings,
+            env,
+            env_remove,
+            args: Vec::new(),
+        })
+    }
+
+    fn parse(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+        _fds: &InheritedFds,
+    ) -> Result<Vec<(RawFd, Option<String>)>, InheritedFdError> {
+        let invalid = |var: &str| InheritedFdError::InvalidEnvVar(var.to_owned());
+        if let Some(pid) = env("LISTEN_PID") {
+            let pid = pid.parse::<u32>().map_err(|_| invalid("LISTEN_PID"))?;
+            if pid != self.listen_pid.unwrap_or_else(std::process::id) {
+                // The file descriptors were meant for some other process.
+                return Ok(Vec::new());
+            }
+        }
+        let Some(count) = env("LISTEN_FDS") else {
+            return Ok(Vec::new());
+        };
+        // Limit the count so that the FD numbers don't overflow.
+        let count = count
+            .parse::<usize>()
+            .ok()
+            .filter(|count| *count <= (RawFd::MAX - SD_LISTEN_FDS_START) as usize)
+            .ok_or_else(|| invalid("LISTEN_FDS"))?;
+        let names = match env("LISTEN_FDNAMES") {
+            Some(names) => {
+                let names = names
+                    .split(':')
+                    .map(|name| Some(name.to_owned()))
+                    .collect::<Vec<_>>();
+                if names.len() != count {
+                    return Err(invalid("LISTEN_FDNAMES"));
+                }
+                names
+            }
+            None => vec![None; count],
+        };
+        Ok((SD_LISTEN_FDS_START..).zip(names).collect())
+    }
+}
+
+/// The protocol used by Einhorn, which keeps the given file descriptor numbers and advertises them
+/// in `EINHORN_FD_COUNT` and `EINHORN_FD_<n>`, as well as the older `EINHORN_FDS`.
+///
+/// Einhorn has no way to pass names, so any names are ignored.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Einhorn;
+
+impl FdProtocol for Einhorn {
+    fn advertise(&self, fds: Vec<NamedFdMapping>) -> Result<Advertisement, ProtocolError> {
+        let child_fds = fds
+            .iter()
+            .map(|fd| fd.mapping.child_fd.to_string())
+            .collect::<Vec<_>>();
+        let mut env = vec![
+            ("EINHORN_FD_COUNT".to_owned(), child_fds.len().to_string()),
+            ("EINHORN_FDS".to_owned(), child_fds.join(" ")),
+        ];
+        env.extend(
+            child_fds
+                .into_iter()
+                .enumerate()
+                .map(|(i, child_fd)| (format!("EINHORN_FD_{i}"), child_fd)),
+        );
+        Ok(Advertisement {
+            mappings: fds.into_iter().map(Into::into).collect(),
+            env,
+            ..Default::default()
+        })
+    }
+
+    fn parse(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+        _fds: &InheritedFds,
+    ) -> Result<Vec<(RawFd, Option<String>)>, InheritedFdError> {
+        let parse_fd = |var: &str, value: &str| {
+            value
+                .parse::<RawFd>()
+                .map(|raw_fd| (raw_fd, None))
+                .map_err(|_| InheritedFdError::InvalidEnvVar(var.to_owned()))
+        };
+        if let Some(count) = env("EINHORN_FD_COUNT") {
+            let count = count
+                .parse::<usize>()
+                .map_err(|_| InheritedFdError::InvalidEnvVar("EINHORN_FD_COUNT".to_owned()))?;
+            (0..count)
+                .map(|i| {
+                    let var = format!("EINHORN_FD_{i}");
+                    let value =
+                        env(&var).ok_or_else(|| InheritedFdError::InvalidEnvVar(var.clone()))?;
+                    parse_fd(&var, &value)
+                })
+                .collect()
+        } else if let Some(fds) = env("EINHORN_FDS") {
+            fds.split_whitespace()
+                .map(|value| parse_fd("EINHORN_FDS", value))
+                .collect()
+        } else {
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Passes file descriptors consecutively starting from `first_fd`, without advertising them, as
+/// done by Go's `os/exec` for `ExtraFiles`.
+///
+/// The child side takes every consecutive inherited file descriptor starting from `first_fd`.
+/// Names are ignored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sequential {
+    /// The file descriptor number of the first file descriptor passed.
+    pub first_fd: RawFd,
+}
+
+impl Default for Sequential {
+    fn default() -> Self {
+        Self { first_fd: 3 }
+    }
+}
+
+impl FdProtocol for Sequential {
+    fn advertise(&self, fds: Vec<NamedFdMapping>) -> Result<Advertisement, ProtocolError> {
+        Ok(Advertisement {
+            mappings: fds
+                .into_iter()
+                .zip(self.first_fd..)
+                .map(|(fd, child_fd)| FdMapping {
+                    parent_fd: fd.mapping.parent_fd,
+                    child_fd,
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn parse(
+        &self,
+        _env: &dyn Fn(&str) -> Option<String>,
+        fds: &InheritedFds,
+    ) -> Result<Vec<(RawFd, Option<String>)>, InheritedFdError> {
+        Ok(fds
+            .iter()
+            .map(|(raw_fd, _)| raw_fd)
+            .skip_while(|&raw_fd| raw_fd < self.first_fd)
+            .zip(self.first_fd..)
+            .take_while(|(raw_fd, expected)| raw_fd == expected)
+            .map(|(raw_fd, _)| (raw_fd, None))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use nix::fcntl::{FcntlArg, fcntl};
+    use std::{
+        collections::HashMap,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+    use tempfile::tempfile;
+
+    fn named(name: Option<&str>, child_fd: RawFd) -> NamedFdMapping {
+        NamedFdMapping {
+            name: name.map(str::to_owned),
+            mapping: FdMapping {
+                parent_fd: tempfile().unwrap().into(),
+                child_fd,
+            },
+        }
+    }
+
+    /// Opens new file descriptors with consecutive numbers starting from at least `min_fd`, and
+    /// adds them to a new registry.
+    fn inherit(min_fd: RawFd, count: usize) -> (InheritedFds, Vec<RawFd>) {
+        let mut fds = InheritedFds::new();
+        let mut raw_fds = Vec::new();
+        let file = tempfile().unwrap();
+        for i in 0..count {
+            let raw_fd = fcntl(&file, FcntlArg::F_DUPFD_CLOEXEC(min_fd + i as RawFd)).unwrap();
+            // SAFETY: We just opened this FD, so nothing else owns it.
+            fds.insert(unsafe { OwnedFd::from_raw_fd(raw_fd) }, None);
+            raw_fds.push(raw_fd);
+        }
+        (fds, raw_fds)
+    }
+
+    /// Converts an advertisement into an environment lookup function.
+    fn env_of(advertisement: &Advertisement) -> impl Fn(&str) -> Option<String> {
+        let env = advertisement.env.iter().cloned().collect::<HashMap<_, _>>();
+        move |var| env.get(var).cloned()
+    }
+
+    #[test]
+    fn command_fds() {
+        setup();
+        let (mut fds, raw_fds) = inherit(700, 2);
+        let advertisement = CommandFds
+            .advertise(vec![named(Some("a"), raw_fds[0]), named(None, raw_fds[1])])
+            .unwrap();
+        assert_eq!(advertisement.mappings[1].child_fd, raw_fds[1]);
+        assert_eq!(
+            fds.apply_protocol_with_env(&CommandFds, &env_of(&advertisement))
+                .unwrap(),
+            vec![raw_fds[0]]
+        );
+        assert_eq!(fds.name(raw_fds[0]), Some("a"));
+
+        assert_eq!(
+            CommandFds
+                .advertise(vec![named(Some("a:b"), 3)])
+                .unwrap_err(),
+            ProtocolError::InvalidName("a:b".to_owned())
+        );
+        assert_eq!(
+            CommandFds.advertise(vec![named(Some(""), 3)]).unwrap_err(),
+            ProtocolError::InvalidName(String::new())
+        );
+    }
+
+    #[test]
+    fn systemd() {
+        setup();
+        let advertisement = Systemd {
+            listen_pid: Some(42),
+        }
+        .advertise(vec![named(Some("http"), 10), named(None, 11)])
+        .unwrap();
+        assert_eq!(
+            advertisement
+                .mappings
+                .iter()
+                .map(|mapping| mapping.child_fd)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        let env = env_of(&advertisement);
+        assert_eq!(env("LISTEN_FDS").as_deref(), Some("2"));
+        assert_eq!(env("LISTEN_FDNAMES").as_deref(), Some("http:unknown"));
+        assert_eq!(env("LISTEN_PID").as_deref(), Some("42"));
+        assert!(advertisement.env_remove.is_empty());
+
+        let fds = InheritedFds::new();
+        assert_eq!(
+            Systemd {
+                listen_pid: Some(42)
+            }
+            .parse(&env, &fds)
+            .unwrap(),
+            vec![
+                (3, Some("http".to_owned())),
+                (4, Some("unknown".to_owned()))
+            ]
+        );
+        // The FDs were meant for PID 42, not this process.
+        assert_eq!(Systemd::default().parse(&env, &fds).unwrap(), vec![]);
+
+        let env = |var: &str| (var == "LISTEN_FDS").then(|| "1".to_owned());
+        assert_eq!(
+            Systemd::default().parse(&env, &fds).unwrap(),
+            vec![(3, None)]
+        );
+        let env = |var: &str| match var {
+            "LISTEN_FDS" => Some("1".to_owned()),
+            "LISTEN_FDNAMES" => Some("a:b".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            Systemd::default().parse(&env, &fds).unwrap_err(),
+            InheritedFdError::InvalidEnvVar("LISTEN_FDNAMES".to_owned())
+        );
+        for count in ["-1", &RawFd::MAX.to_string(), "18446744073709551616"] {
+            let env = |var: &str| (var == "LISTEN_FDS").then(|| count.to_owned());
+            assert_eq!(
+                Systemd::default().parse(&env, &fds).unwrap_err(),
+                InheritedFdError::InvalidEnvVar("LISTEN_FDS".to_owned())
+            );
+        }
+
+        assert!(
+            Systemd::default()
+                .advertise(vec![named(Some(""), 3)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn einhorn() {
+        setup();
+        let advertisement = Einhorn
+            .advertise(vec![named(Some("ignored"), 5), named(None, 7)])
+            .unwrap();
+        let env = env_of(&advertisement);
+        assert_eq!(env("EINHORN_FDS").as_deref(), Some("5 7"));
+        assert_eq!(env("EINHORN_FD_COUNT").as_deref(), Some("2"));
+        assert_eq!(env("EINHORN_FD_1").as_deref(), Some("7"));
+        let fds = InheritedFds::new();
+        assert_eq!(
+            Einhorn.parse(&env, &fds).unwrap(),
+            vec![(5, None), (7, None)]
+        );
+
+        let env = |var: &str| (var == "EINHORN_FDS").then(|| "3 4".to_owned());
+        assert_eq!(
+            Einhorn.parse(&env, &fds).unwrap(),
+            vec![(3, None), (4, None)]
+        );
+    }
+
+    #[test]
+    fn sequential() {
+        setup();
+        let advertisement = Sequential::default()
+            .advertise(vec![named(Some("a"), 10), named(None, 11)])
+            .unwrap();
+        assert!(advertisement.env.is_empty());
+        assert_eq!(advertisement.mappings[0].child_fd, 3);
+        assert_eq!(advertisement.mappings[1].child_fd, 4);
+
+        let (mut fds, raw_fds) = inherit(800, 3);
+        assert_eq!(raw_fds, vec![800, 801, 802]);
+        let (mut after_gap, _) = inherit(804, 1);
+        fds.insert(after_gap.take(804).unwrap(), None);
+        // Only the consecutive FDs from `first_fd` are taken.
+        assert_eq!(
+            fds.apply_protocol_with_env(&Sequential { first_fd: 801 }, &|_| None)
+                .unwrap(),
+            vec![801, 802]
+        );
+        assert_eq!(
+            fds.apply_protocol_with_env(&Sequential { first_fd: 800 }, &|_| None)
+                .unwrap(),
+            vec![800, 801, 802]
+        );
+        assert_eq!(fds.take(800).unwrap().as_raw_fd(), 800);
+    }
+
+    #[test]
+    fn apply_to_child() {
+        setup();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            r#"[ "$LISTEN_FDS" = 1 ] && [ "$LISTEN_FDNAMES" = a ] && [ -e /proc/self/fd/3 ] && [ "$1" = arg ]"#,
+        );
+        command.arg("sh");
+        let mut advertisement = Systemd::default()
+            .advertise(vec![named(Some("a"), 9)])
+            .unwrap();
+        advertisement.args.push("arg".to_owned());
+        advertisement.apply(&mut command).unwrap();
+        assert!(command.status().unwrap().success());
+    }
+}
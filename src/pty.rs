@@ -0,0 +1,411 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running a child process on a pseudo-terminal, as its controlling terminal.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::pty::{Pty, WindowSize};
+//! use std::io::Read;
+//! use std::process::Command;
+//!
+//! let pty = Pty::open(Some(WindowSize { rows: 24, cols: 80 })).unwrap();
+//! let mut command = Command::new("top");
+//! let mut master = pty.attach(&mut command).unwrap();
+//! let mut child = command.spawn().unwrap();
+//! // Drop the command so that the parent no longer holds the terminal open.
+//! drop(command);
+//!
+//! let mut buf = [0; 1024];
+//! let len = master.read(&mut buf).unwrap();
+//! ```
+
+use crate::{FdAction, prepare_fd_actions};
+use nix::{
+    errno::Errno,
+    fcntl::{OFlag, open},
+    libc,
+    pty::{grantpt, posix_openpt, ptsname_r, unlockpt},
+    sys::stat::Mode,
+    unistd::setsid,
+};
+use std::{
+    fs::File,
+    io,
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::Command,
+};
+
+/// The size of a terminal window, in characters.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WindowSize {
+    /// The number of rows.
+    pub rows: u16,
+    /// The number of columns.
+    pub cols: u16,
+}
+
+/// Returns the window size of the given terminal.
+pub fn window_size(terminal: impl AsFd) -> io::Result<WindowSize> {
+    let mut winsize = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `TIOCGWINSZ` only writes to the `winsize` we pass it.
+    Errno::result(unsafe {
+        libc::ioctl(terminal.as_fd().as_raw_fd(), libc::TIOCGWINSZ, &mut winsize)
+    })?;
+    Ok(WindowSize {
+        rows: winsize.ws_row,
+        cols: winsize.ws_col,
+    })
+}
+
+/// Sets the window size of the given terminal, which may be either side of a pseudo-terminal.
+///
+/// The foreground process group of the terminal is sent `SIGWINCH` if the size changes.
+pub fn set_window_size(terminal: impl AsFd, size: WindowSize) -> io::Result<()> {
+    let winsize = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `TIOCSWINSZ` only reads from the `winsize` we pass it.
+    Errno::result(unsafe {
+        libc::ioctl(terminal.as_fd().as_raw_fd(), libc::TIOCSWINSZ, &winsize)
+    })?;
+    Ok(())
+}
+
+/// A newly opened pseudo-terminal pair, ready to be attached to a child process.
+#[derive(Debug)]
+pub struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    /// Opens a new pseudo-terminal pair, optionally setting its window size.
+    ///
+    /// Both sides are opened with `O_CLOEXEC`, so they aren't leaked to other child processes.
+    pub fn open(size: Option<WindowSize>) -> io::Result<Self> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let slave = open(
+            ptsname_r(&master)?.as_str(),
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let master = OwnedFd::from(master);
+        if let Some(size) = size {
+            set_window_size(&master, size)?;
+        }
+        Ok(Self { master, slave })
+    }
+
+    /// Attaches the slave side of the pseudo-terminal to the command as its stdin, stdout and
+    /// stderr and controlling terminal, and returns the master side.
+    ///
+    /// See [`attach_to`](Self::attach_to).
+    pub fn attach(self, command: &mut Command) -> io::Result<File> {
+        self.attach_to(command, &[0, 1, 2])
+    }
+
+    /// Attaches the slave side of the pseudo-terminal to the command as the given child FDs, and
+    /// returns the master side.
+    ///
+    /// In the child, this starts a new session with `setsid`, maps the slave to the given FDs, then
+    /// makes it the controlling terminal of the session. The slave is mapped after the standard
+    /// streams are set up by [`Command`], so mapping it to 0, 1 or 2 overrides them.
+    ///
+    /// Note that the `Command` holds the slave side open until it is dropped, so reads from the
+    /// master side won't fail with `EIO` after the child exits until then. As with
+    /// [`CommandFdExt::fd_mappings`](crate::CommandFdExt::fd_mappings), this shouldn't be combined
+    /// with other mappings on the same command.
+    ///
+    /// To use this with tokio, convert the `Command` into a `tokio::process::Command` afterwards,
+    /// and wrap the master side in a `pty::tokio::PtyMaster` if the `tokio` feature is enabled.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidInput`] if `child_fds` is empty, or if the
+    /// FDs are invalid as described for [`FdActionError`](crate::FdActionError).
+    pub fn attach_to(self, command: &mut Command, child_fds: &[RawFd]) -> io::Result<File> {
+        let Some(&controlling_fd) = child_fds.first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No child FDs to attach the pseudo-terminal to",
+            ));
+        };
+        let mut ops = prepare_fd_actions(vec![FdAction::Map {
+            parent_fd: self.slave,
+            child_fds: child_fds.to_vec(),
+        }])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        // Safety: `setsid`, `ChildFdOps::apply` and `ioctl` will not allocate, so they are safe to
+        // call from this hook.
+        unsafe {
+            command.pre_exec(move || {
+                setsid()?;
+                ops.apply()?;
+                Errno::result(libc::ioctl(controlling_fd, libc::TIOCSCTTY, 0))?;
+                Ok(())
+            });
+        }
+
+        Ok(self.master.into())
+    }
+}
+
+/// An asynchronous wrapper for the master side of a pseudo-terminal for use with tokio.
+#[cfg(feature = "tokio")]
+pub mod tokio {
+    use super::WindowSize;
+    use nix::{
+        fcntl::{FcntlArg, OFlag, fcntl},
+        libc,
+    };
+    use std::{
+        fs::File,
+        io::{self, Read, Write},
+        os::fd::{AsFd, BorrowedFd, OwnedFd},
+        pin::Pin,
+        task::{Context, Poll, ready},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd};
+
+    /// The master side of a pseudo-terminal, for asynchronous reading and writing.
+    ///
+    /// Unlike reading from the master side directly, which fails with `EIO` once the slave side
+    /// has been closed by every process, this treats that as the end of the stream. This must be
+    /// used within a tokio runtime.
+    #[derive(Debug)]
+    pub struct PtyMaster {
+        inner: AsyncFd<File>,
+    }
+
+    impl PtyMaster {
+        /// Wraps the master side of a pseudo-terminal, as returned by
+        /// [`Pty::attach`](super::Pty::attach), setting it to non-blocking mode.
+        pub fn new(master: impl Into<OwnedFd>) -> io::Result<Self> {
+            let master = File::from(master.into());
+            let flags = OFlag::from_bits_retain(fcntl(&master, FcntlArg::F_GETFL)?);
+            fcntl(&master, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+            Ok(Self {
+                inner: AsyncFd::new(master)?,
+            })
+        }
+
+        /// Returns the window size of the pseudo-terminal.
+        pub fn window_size(&self) -> io::Result<WindowSize> {
+            super::window_size(self)
+        }
+
+        /// Sets the window size of the pseudo-terminal.
+        pub fn set_window_size(&self, size: WindowSize) -> io::Result<()> {
+            super::set_window_size(self, size)
+        }
+    }
+
+    impl AsFd for PtyMaster {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.inner.get_ref().as_fd()
+        }
+    }
+
+    impl AsyncRead for PtyMaster {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                    Ok(Ok(len)) => {
+                        buf.advance(len);
+                        return Poll::Ready(Ok(()));
+                    }
+                    // The slave side has been closed, so this is the end of the stream.
+                    Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => {
+                        return Poll::Ready(Ok(()));
+                    }
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for PtyMaster {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+                match guard.try_io(|inner| inner.get_ref().write(buf)) {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FdActionError, FdMappingCollision, tests::setup};
+    use std::io::{Read, Write};
+
+    /// Reads from the master side of a pseudo-terminal until the slave side is closed.
+    fn read_to_eio(master: &mut File) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match master.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => output.extend_from_slice(&buf[..len]),
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) => panic!("Error reading from pseudo-terminal: {e}"),
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn controlling_terminal() {
+        setup();
+
+        let pty = Pty::open(Some(WindowSize { rows: 24, cols: 80 })).unwrap();
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("stty size; test -t 0 && test -t 2 && echo ctty > /dev/tty");
+        let mut master = pty.attach(&mut command).unwrap();
+        assert_eq!(
+            window_size(&master).unwrap(),
+            WindowSize { rows: 24, cols: 80 }
+        );
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        let output = read_to_eio(&mut master);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, b"24 80\r\nctty\r\n");
+    }
+
+    #[test]
+    fn input_and_resize() {
+        setup();
+
+        let pty = Pty::open(None).unwrap();
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("read line; stty size; echo \"got $line\"");
+        let mut master = pty.attach(&mut command).unwrap();
+        set_window_size(
+            &master,
+            WindowSize {
+                rows: 50,
+                cols: 132,
+            },
+        )
+        .unwrap();
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        master.write_all(b"hello\n").unwrap();
+
+        let output = read_to_eio(&mut master);
+        assert!(child.wait().unwrap().success());
+        // The input is echoed by the terminal as soon as it is written.
+        assert_eq!(output, b"hello\r\n50 132\r\ngot hello\r\n");
+    }
+
+    #[test]
+    fn collision() {
+        setup();
+
+        let pty = Pty::open(None).unwrap();
+        let error = pty
+            .attach_to(&mut Command::new("true"), &[0, 0])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<FdActionError>(),
+            Some(&FdActionError::Collision(FdMappingCollision))
+        );
+    }
+
+    #[test]
+    fn no_child_fds() {
+        setup();
+
+        let pty = Pty::open(None).unwrap();
+        assert_eq!(
+            pty.attach_to(&mut Command::new("true"), &[])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[::tokio::test(crate = "::tokio")]
+    async fn tokio_master() {
+        use ::tokio::io::AsyncReadExt;
+
+        setup();
+
+        let pty = Pty::open(None).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("stty size");
+        let master = pty.attach(&mut command).unwrap();
+        let mut master = super::tokio::PtyMaster::new(master).unwrap();
+        master
+            .set_window_size(WindowSize {
+                rows: 30,
+                cols: 100,
+            })
+            .unwrap();
+        let mut command = ::tokio::process::Command::from(command);
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        let mut output = Vec::new();
+        master.read_to_end(&mut output).await.unwrap();
+        assert!(child.wait().await.unwrap().success());
+        assert_eq!(output, b"30 100\r\n");
+    }
+}
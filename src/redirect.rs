@@ -0,0 +1,487 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shell-style redirections such as `3<input.txt 4>>log 2>&1 7>&-`.
+//!
+//! A redirection string is a whitespace-separated list of redirections, each of which is one of:
+//!
+//! - `[n]<path` to open `path` for reading as FD `n`, which defaults to 0;
+//! - `[n]>path` or `[n]>|path` to create or truncate `path` for writing as FD `n`, which defaults to
+//!   1;
+//! - `[n]>>path` to create `path` or open it for appending as FD `n`, which defaults to 1;
+//! - `[n]<>path` to create `path` or open it for reading and writing as FD `n`, which defaults to
+//!   0;
+//! - `[n]>&m` or `[n]<&m` to make FD `n` a duplicate of FD `m`, where `n` defaults to 1 for `>&`
+//!   and 0 for `<&`;
+//! - `[n]>&-` or `[n]<&-` to close FD `n`.
+//!
+//! The path may also be given as a separate word, as in `2> errors.log`. Quoting isn't supported,
+//! so paths can't contain whitespace.
+//!
+//! As in a POSIX shell, redirections are applied in the child from left to right, after the
+//! standard streams have been set up by [`Command`]. So `2>&1 1>file` sends stderr to wherever
+//! stdout originally went and stdout to `file`, while `1>file 2>&1` sends both to `file`. Files are
+//! opened in the parent when the redirections are applied to a command.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use command_fds::redirect::Redirections;
+//! use std::process::Command;
+//!
+//! let redirections: Redirections = "3<input.txt 4>>log 2>&1 7>&-".parse().unwrap();
+//! let mut command = Command::new("wrapped");
+//! redirections.apply(&mut command).unwrap();
+//! let mut child = command.spawn().unwrap();
+//! ```
+
+use crate::{ChildFdOp, ChildFdOps};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{File, OpenOptions},
+    io,
+    os::{
+        fd::{OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Errors that can occur while parsing or applying redirections.
+#[derive(Debug, Error)]
+pub enum RedirectError {
+    /// The redirection string couldn't be parsed
+    #[error("Invalid redirection {redirection:?}: {reason}")]
+    Parse {
+        /// The redirection which couldn't be parsed.
+        redirection: String,
+        /// Why it couldn't be parsed.
+        reason: String,
+    },
+
+    /// Error opening a file for a redirection
+    #[error("Failed to open {path:?}: {source}")]
+    Open {
+        /// The path of the file which couldn't be opened.
+        path: PathBuf,
+        /// The underlying error.
+        source: io::Error,
+    },
+
+    /// A redirection refers to a negative file descriptor number
+    #[error("Invalid FD number in redirection {0}")]
+    InvalidFd(Redirection),
+}
+
+/// How a file is opened for a redirection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OpenMode {
+    /// Opened for reading, as with `<`.
+    Read,
+    /// Created or truncated and opened for writing, as with `>`.
+    Write,
+    /// Created if necessary and opened for appending, as with `>>`.
+    Append,
+    /// Created if necessary and opened for reading and writing, as with `<>`.
+    ReadWrite,
+}
+
+impl OpenMode {
+    /// Opens the given path in this mode, with `O_CLOEXEC` set.
+    fn open(self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match self {
+            Self::Read => options.read(true),
+            Self::Write => options.write(true).create(true).truncate(true),
+            Self::Append => options.append(true).create(true),
+            Self::ReadWrite => options.read(true).write(true).create(true),
+        };
+        options.open(path)
+    }
+
+    /// Returns the shell operator for this mode.
+    fn operator(self) -> &'static str {
+        match self {
+            Self::Read => "<",
+            Self::Write => ">",
+            Self::Append => ">>",
+            Self::ReadWrite => "<>",
+        }
+    }
+}
+
+/// A single redirection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Redirection {
+    /// Opens a file as the given child FD.
+    Open {
+        /// The child FD to open the file as.
+        fd: RawFd,
+        /// The path of the file to open.
+        path: PathBuf,
+        /// How to open the file.
+        mode: OpenMode,
+    },
+    /// Makes the child FD `fd` a duplicate of whatever the child FD `source` is at that point.
+    Dup {
+        /// The child FD to replace.
+        fd: RawFd,
+        /// The child FD to duplicate.
+        source: RawFd,
+    },
+    /// Closes the child FD.
+    Close {
+        /// The child FD to close.
+        fd: RawFd,
+    },
+}
+
+impl Redirection {
+    /// Returns whether all file descriptor numbers in the redirection are valid.
+    fn fds_valid(&self) -> bool {
+        match self {
+            Self::Open { fd, .. } | Self::Close { fd } => *fd >= 0,
+            Self::Dup { fd, source } => *fd >= 0 && *source >= 0,
+        }
+    }
+}
+
+impl Display for Redirection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Open { fd, path, mode } => {
+                write!(f, "{fd}{}{}", mode.operator(), path.display())
+            }
+            Self::Dup { fd, source } => write!(f, "{fd}>&{source}"),
+            Self::Close { fd } => write!(f, "{fd}>&-"),
+        }
+    }
+}
+
+/// A list of redirections, applied from left to right.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Redirections {
+    redirections: Vec<Redirection>,
+}
+
+impl Redirections {
+    /// Creates an empty list of redirections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a redirection to the list, to be applied after those already in it.
+    pub fn push(&mut self, redirection: Redirection) -> &mut Self {
+        self.redirections.push(redirection);
+        self
+    }
+
+    /// Returns the redirections in the order in which they will be applied.
+    pub fn redirections(&self) -> &[Redirection] {
+        &self.redirections
+    }
+
+    /// Opens any files needed by the redirections, and arranges for the redirections to be applied
+    /// in the child process when the command is spawned.
+    ///
+    /// Note that the `Command` takes ownership of the opened files, which means that they won't be
+    /// closed in the parent process until the `Command` is dropped.
+    pub fn apply<'a>(&self, command: &'a mut Command) -> Result<&'a mut Command, RedirectError> {
+        // Otherwise negative FD numbers would be passed straight to `dup2` and `close` in the
+        // child.
+        if let Some(redirection) = self.redirections.iter().find(|r| !r.fds_valid()) {
+            return Err(RedirectError::InvalidFd(redirection.clone()));
+        }
+        let mut parent_fds: Vec<OwnedFd> = Vec::new();
+        let mut ops = Vec::with_capacity(self.redirections.len());
+        for redirection in &self.redirections {
+            ops.push(match redirection {
+                Redirection::Open { fd, path, mode } => {
+                    let file = mode.open(path).map_err(|source| RedirectError::Open {
+                        path: path.clone(),
+                        source,
+                    })?;
+                    parent_fds.push(file.into());
+                    ChildFdOp::Map {
+                        parent_index: parent_fds.len() - 1,
+                        child_fd: *fd,
+                    }
+                }
+                Redirection::Dup { fd, source } => ChildFdOp::Dup {
+                    source: *source,
+                    target: *fd,
+                },
+                Redirection::Close { fd } => ChildFdOp::Close(*fd),
+            });
+        }
+
+        let mut ops = ChildFdOps {
+            parent_fds,
+            ops,
+            ..Default::default()
+        };
+        // Safety: `ChildFdOps::apply` will not allocate, so it is safe to call from this hook.
+        unsafe {
+            command.pre_exec(move || ops.apply());
+        }
+
+        Ok(command)
+    }
+}
+
+impl From<Vec<Redirection>> for Redirections {
+    fn from(redirections: Vec<Redirection>) -> Self {
+        Self { redirections }
+    }
+}
+
+impl Display for Redirections {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, redirection) in self.redirections.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{redirection}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Redirections {
+    type Err = RedirectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut redirections = Vec::new();
+        let mut words = s.split_whitespace();
+        while let Some(word) = words.next() {
+            let invalid = |reason: &str| RedirectError::Parse {
+                redirection: word.to_owned(),
+                reason: reason.to_owned(),
+            };
+
+            let operator_start = word
+                .find(['<', '>'])
+                .ok_or_else(|| invalid("missing operator"))?;
+            let (fd, rest) = word.split_at(operator_start);
+            let fd = if fd.is_empty() {
+                None
+            } else {
+                Some(parse_fd(fd).ok_or_else(|| invalid("invalid FD"))?)
+            };
+
+            let (operator, target) = ["<>", ">>", ">|", ">&", "<&", "<", ">"]
+                .into_iter()
+                .find_map(|operator| rest.strip_prefix(operator).map(|target| (operator, target)))
+                .unwrap();
+            let target = if target.is_empty() {
+                words.next().ok_or_else(|| invalid("missing target"))?
+            } else {
+                target
+            };
+            let default_fd = if operator.starts_with('<') { 0 } else { 1 };
+            let fd = fd.unwrap_or(default_fd);
+
+            let mode = match operator {
+                "<" => OpenMode::Read,
+                ">" | ">|" => OpenMode::Write,
+                ">>" => OpenMode::Append,
+                "<>" => OpenMode::ReadWrite,
+                _ => {
+                    redirections.push(if target == "-" {
+                        Redirection::Close { fd }
+                    } else {
+                        Redirection::Dup {
+                            fd,
+                            source: parse_fd(target).ok_or_else(|| invalid("invalid source FD"))?,
+                        }
+                    });
+                    continue;
+                }
+            };
+            redirections.push(Redirection::Open {
+                fd,
+                path: target.into(),
+                mode,
+            });
+        }
+        Ok(Self { redirections })
+    }
+}
+
+/// Parses a non-negative decimal FD number.
+fn parse_fd(s: &str) -> Option<RawFd> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::fs::read_to_string;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parse() {
+        let redirections: Redirections = "3<input.txt 4>>log 5<>sock 6>&1 7>&- <in >out 2> err <&4"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redirections.redirections(),
+            [
+                Redirection::Open {
+                    fd: 3,
+                    path: "input.txt".into(),
+                    mode: OpenMode::Read,
+                },
+                Redirection::Open {
+                    fd: 4,
+                    path: "log".into(),
+                    mode: OpenMode::Append,
+                },
+                Redirection::Open {
+                    fd: 5,
+                    path: "sock".into(),
+                    mode: OpenMode::ReadWrite,
+                },
+                Redirection::Dup { fd: 6, source: 1 },
+                Redirection::Close { fd: 7 },
+                Redirection::Open {
+                    fd: 0,
+                    path: "in".into(),
+                    mode: OpenMode::Read,
+                },
+                Redirection::Open {
+                    fd: 1,
+                    path: "out".into(),
+                    mode: OpenMode::Write,
+                },
+                Redirection::Open {
+                    fd: 2,
+                    path: "err".into(),
+                    mode: OpenMode::Write,
+                },
+                Redirection::Dup { fd: 0, source: 4 },
+            ]
+        );
+        assert_eq!(
+            redirections.to_string(),
+            "3<input.txt 4>>log 5<>sock 6>&1 7>&- 0<in 1>out 2>err 0>&4"
+        );
+        assert_eq!(
+            redirections.to_string().parse::<Redirections>().unwrap(),
+            redirections
+        );
+        assert_eq!("".parse::<Redirections>().unwrap(), Redirections::new());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for redirection in [
+            "3", "x<file", "-1>file", "+1>file", "2>", "2>&x", "2>&-1", ">&file",
+        ] {
+            assert!(
+                matches!(
+                    redirection.parse::<Redirections>(),
+                    Err(RedirectError::Parse { .. })
+                ),
+                "{redirection:?} should fail to parse"
+            );
+        }
+    }
+
+    #[test]
+    fn shell_semantics() {
+        setup();
+
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let log = dir.path().join("log");
+        std::fs::write(&log, "first\n").unwrap();
+
+        let redirections: Redirections = format!(
+            "2>&1 1>{} 4>>{} 5<{} 5>&- 6<&0",
+            out.display(),
+            log.display(),
+            log.display()
+        )
+        .parse()
+        .unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            "echo stdout; echo stderr >&2; echo second >&4; \
+             test -e /proc/self/fd/5 || echo closed >&2",
+        );
+        redirections.apply(&mut command).unwrap();
+        let output = command.output().unwrap();
+
+        assert!(output.status.success());
+        // Stderr was redirected to where stdout originally went, before stdout went to the file.
+        assert_eq!(output.stdout, b"stderr\nclosed\n");
+        assert_eq!(output.stderr, b"");
+        assert_eq!(read_to_string(&out).unwrap(), "stdout\n");
+        assert_eq!(read_to_string(&log).unwrap(), "first\nsecond\n");
+    }
+
+    #[test]
+    fn read_write() {
+        setup();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+
+        let redirections: Redirections = format!("3<>{}", path.display()).parse().unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo hello >&3");
+        redirections.apply(&mut command).unwrap();
+        assert!(command.status().unwrap().success());
+        assert_eq!(read_to_string(&path).unwrap(), "hello\n");
+
+        let redirections: Redirections = format!("<{}", path.display()).parse().unwrap();
+        let mut command = Command::new("cat");
+        redirections.apply(&mut command).unwrap();
+        assert_eq!(command.output().unwrap().stdout, b"hello\n");
+    }
+
+    #[test]
+    fn open_error() {
+        let redirections: Redirections = "3</nonexistent/file".parse().unwrap();
+        let mut command = Command::new("true");
+        assert!(matches!(
+            redirections.apply(&mut command),
+            Err(RedirectError::Open { path, .. }) if path == Path::new("/nonexistent/file")
+        ));
+    }
+
+    #[test]
+    fn invalid_fd() {
+        let mut redirections = Redirections::new();
+        redirections
+            .push(Redirection::Close { fd: 3 })
+            .push(Redirection::Dup { fd: 3, source: -1 });
+        let mut command = Command::new("true");
+        assert!(matches!(
+            redirections.apply(&mut command),
+            Err(RedirectError::InvalidFd(Redirection::Dup {
+                fd: 3,
+                source: -1
+            }))
+        ));
+    }
+}
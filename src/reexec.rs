@@ -0,0 +1,327 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for zero-downtime upgrades, by re-executing the current program while keeping its
+//! listening sockets open.
+//!
+//! The running process uses [`Reexec`] to spawn the new version of the program, passing it
+//! duplicates of its listening sockets, and waits for the new process to signal that it is ready.
+//! The old process can then stop accepting connections, finish serving existing ones and exit.
+//!
+//! The new process finds its listening sockets by name through the [`inherited`] module, and
+//! calls [`notify_ready`] once it is ready to accept connections.
+//!
+//! # Example
+//!
+//! In the old process:
+//!
+//! ```no_run
+//! use command_fds::reexec::Reexec;
+//! use std::net::TcpListener;
+//! use std::os::fd::AsFd;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! // ... serve until asked to upgrade ...
+//!
+//! let mut reexec = Reexec::new().unwrap();
+//! reexec.listener("http", listener.as_fd()).unwrap();
+//! let new_process = reexec.spawn().unwrap();
+//!
+//! // The new process is now accepting connections too, so stop accepting and exit once existing
+//! // connections are finished.
+//! drop(listener);
+//! ```
+//!
+//! In the new process:
+//!
+//! ```no_run
+//! use command_fds::inherited::{
+//!     InheritedFds, init_inherited_fds, take_named_fd_ownership, with_inherited_fds,
+//! };
+//! use command_fds::reexec::notify_ready;
+//! use std::net::TcpListener;
+//!
+//! // SAFETY: This is called before anything else in the program.
+//! unsafe {
+//!     init_inherited_fds().unwrap();
+//! }
+//! with_inherited_fds(InheritedFds::set_names_from_env).unwrap().unwrap();
+//!
+//! // Use the listener from the old process if there was one, or bind a new one otherwise.
+//! let listener = match take_named_fd_ownership("http") {
+//!     Ok(fd) => TcpListener::from(fd),
+//!     Err(_) => TcpListener::bind("127.0.0.1:8080").unwrap(),
+//! };
+//!
+//! // Let the old process know that it can stop accepting connections.
+//! notify_ready().unwrap();
+//! ```
+//!
+//! [`inherited`]: crate::inherited
+
+use crate::{
+    CommandFdExt, FdMapping, NamedFdMapping,
+    inherited::{
+        FD_NAMES_ENV_VAR, InheritedFdError, fd_from_parent_env, format_fd_names, is_valid_fd_name,
+        set_fd_env, with_inherited_fds,
+    },
+};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+    unistd::pipe2,
+};
+use std::{
+    env::{args_os, current_exe},
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsFd, BorrowedFd, OwnedFd, RawFd},
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// The environment variable used to tell the new process which file descriptor to signal
+/// readiness on. See [`PARENT_PID_ENV_SUFFIX`](crate::inherited::PARENT_PID_ENV_SUFFIX) for how it
+/// is checked.
+pub const READY_FD_ENV_VAR: &str = "COMMAND_FDS_READY_FD";
+
+/// The first file descriptor number used for listeners in the new process.
+const FIRST_LISTENER_FD: RawFd = 3;
+
+/// Errors that can occur while re-executing the program.
+#[derive(Debug, Error)]
+pub enum ReexecError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Error taking an inherited file descriptor
+    #[error(transparent)]
+    Inherited(#[from] InheritedFdError),
+
+    /// Listener name is not valid
+    #[error("Invalid listener name {0:?}")]
+    InvalidName(String),
+
+    /// The new process exited without becoming ready
+    #[error("New process exited or closed its readiness FD without becoming ready")]
+    NotReady,
+
+    /// The new process didn't become ready in time
+    #[error("Timed out waiting for new process to become ready")]
+    Timeout,
+}
+
+/// A builder to spawn a new version of the current program, passing it the current program's
+/// listening sockets.
+#[derive(Debug)]
+pub struct Reexec {
+    command: Command,
+    listeners: Vec<NamedFdMapping>,
+    ready_timeout: Option<Duration>,
+}
+
+impl Reexec {
+    /// Prepares to re-execute the current executable with the same arguments as this process.
+    ///
+    /// If the executable has been replaced on disk by a new version, then the new version will be
+    /// run.
+    pub fn new() -> io::Result<Self> {
+        let mut command = Command::new(current_exe()?);
+        command.args(args_os().skip(1));
+        Ok(Self::with_command(command))
+    }
+
+    /// Prepares to run the given command as the new process.
+    pub fn with_command(command: Command) -> Self {
+        Self {
+            command,
+            listeners: Vec::new(),
+            ready_timeout: None,
+        }
+    }
+
+    /// Returns the command which will be run, so that its arguments or environment can be changed.
+    pub fn command_mut(&mut self) -> &mut Command {
+        &mut self.command
+    }
+
+    /// Adds a listening socket to pass to the new process with the given name.
+    ///
+    /// The socket is duplicated, so the current process can keep accepting connections on it
+    /// until the new process is ready. Names must be non-empty and may not contain `:`.
+    pub fn listener(&mut self, name: &str, fd: BorrowedFd) -> Result<&mut Self, ReexecError> {
+        if !is_valid_fd_name(name) {
+            return Err(ReexecError::InvalidName(name.to_owned()));
+        }
+        let child_fd = FIRST_LISTENER_FD + self.listeners.len() as RawFd;
+        self.listeners.push(NamedFdMapping {
+            name: Some(name.to_owned()),
+            mapping: FdMapping {
+                parent_fd: fd.try_clone_to_owned()?,
+                child_fd,
+            },
+        });
+        Ok(self)
+    }
+
+    /// Sets how long to wait for the new process to become ready before giving up and killing it.
+    ///
+    /// By default there is no timeout.
+    pub fn ready_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    /// Spawns the new process, and waits for it to call [`notify_ready`].
+    ///
+    /// If the new process exits or closes its readiness file descriptor without calling
+    /// [`notify_ready`], or the timeout expires, then it is killed and an error is returned.
+    pub fn spawn(self) -> Result<Child, ReexecError> {
+        let Self {
+            mut command,
+            listeners,
+            ready_timeout,
+        } = self;
+
+        let (ready_read, ready_write) = pipe2(OFlag::O_CLOEXEC).map_err(io::Error::from)?;
+        let ready_fd = FIRST_LISTENER_FD + listeners.len() as RawFd;
+        command.env(FD_NAMES_ENV_VAR, format_fd_names(&listeners));
+        set_fd_env(&mut command, READY_FD_ENV_VAR, ready_fd);
+        let mut mappings: Vec<FdMapping> = listeners.into_iter().map(Into::into).collect();
+        mappings.push(FdMapping {
+            parent_fd: ready_write,
+            child_fd: ready_fd,
+        });
+        command
+            .fd_mappings(mappings)
+            .expect("Listener FDs should be distinct");
+
+        let mut child = command.spawn()?;
+        // Close our copies of the mapped FDs, in particular the write end of the readiness pipe, so
+        // that we see EOF if the child exits without becoming ready.
+        drop(command);
+
+        match wait_for_ready(ready_read, ready_timeout) {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Waits for a byte to be written to the given readiness pipe.
+fn wait_for_ready(ready_read: OwnedFd, timeout: Option<Duration>) -> Result<(), ReexecError> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => {
+                PollTimeout::try_from(deadline.saturating_duration_since(Instant::now()))
+                    .unwrap_or(PollTimeout::MAX)
+            }
+            None => PollTimeout::NONE,
+        };
+        let mut poll_fds = [PollFd::new(ready_read.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut poll_fds, timeout) {
+            Ok(0) => return Err(ReexecError::Timeout),
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(io::Error::from(e).into()),
+        }
+    }
+
+    let mut buffer = [0];
+    if File::from(ready_read).read(&mut buffer)? == 0 {
+        Err(ReexecError::NotReady)
+    } else {
+        Ok(())
+    }
+}
+
+/// Signals to the old process which spawned this process with [`Reexec`] that this process is
+/// ready, and the old process can stop accepting connections.
+///
+/// [`init_inherited_fds`](crate::inherited::init_inherited_fds) must have been called first, as
+/// the readiness file descriptor is taken from the inherited file descriptors. If this process
+/// wasn't spawned by [`Reexec`], or its parent process isn't the one which spawned it with
+/// [`Reexec`], then this does nothing.
+pub fn notify_ready() -> Result<(), ReexecError> {
+    let Some(raw_fd) = fd_from_parent_env(READY_FD_ENV_VAR)? else {
+        return Ok(());
+    };
+    let mut ready = File::from(with_inherited_fds(|fds| fds.take(raw_fd))??);
+    ready.write_all(&[1])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use std::net::TcpListener;
+
+    fn sh_command(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn spawn_ready() {
+        setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut reexec = Reexec::with_command(sh_command(
+            r#"[ "$COMMAND_FDS_NAMES" = 3=http ] && [ -S /proc/self/fd/3 ] && printf x >&4"#,
+        ));
+        reexec.listener("http", listener.as_fd()).unwrap();
+        let mut child = reexec.spawn().unwrap();
+
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn exit_without_ready() {
+        setup();
+
+        let reexec = Reexec::with_command(sh_command("exit 0"));
+        assert!(matches!(reexec.spawn(), Err(ReexecError::NotReady)));
+    }
+
+    #[test]
+    fn ready_timeout() {
+        setup();
+
+        let mut reexec = Reexec::with_command(sh_command("sleep 10"));
+        reexec.ready_timeout(Duration::from_millis(100));
+        assert!(matches!(reexec.spawn(), Err(ReexecError::Timeout)));
+    }
+
+    #[test]
+    fn invalid_name() {
+        setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut reexec = Reexec::with_command(sh_command("exit 0"));
+        assert!(matches!(
+            reexec.listener("a:b", listener.as_fd()),
+            Err(ReexecError::InvalidName(_))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
-    CommandFdExt, FdMapping, FdMappingCollision, map_fds, preserve_fds, validate_child_fds,
+    CommandFdExt, FdAction, FdActionError, FdMapping, FdMappingCollision, map_fds,
+    prepare_fd_actions, preserve_fds, validate_child_fds,
 };
 use std::os::fd::OwnedFd;
 use tokio::process::Command;
@@ -25,4 +26,15 @@ impl CommandFdExt for Command {
 
         self
     }
+
+    fn fd_actions(&mut self, actions: Vec<FdAction>) -> Result<&mut Self, FdActionError> {
+        let mut ops = prepare_fd_actions(actions)?;
+
+        // Safety: `ChildFdOps::apply` will not allocate, so it is safe to call from this hook.
+        unsafe {
+            self.pre_exec(move || ops.apply());
+        }
+
+        Ok(self)
+    }
 }
@@ -0,0 +1,536 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for passing named file descriptors to an already-running process over a Unix domain
+//! socket, using `SCM_RIGHTS`.
+//!
+//! Each call to [`send_fds`] sends a batch of file descriptors along with a name for each, which
+//! are received together by a single call to [`recv_fds`] on the other end of the socket. Received
+//! file descriptors may be added to an [`InheritedFds`] registry with [`recv_fds_into`] or
+//! [`recv_inherited_fds`], so that they can be taken by name.
+//!
+//! # Example
+//!
+//! ```
+//! use command_fds::transfer::{recv_fds, send_fds};
+//! use std::fs::File;
+//! use std::os::fd::AsFd;
+//! use std::os::unix::net::UnixStream;
+//!
+//! let (sender, receiver) = UnixStream::pair().unwrap();
+//! let file = File::open("Cargo.toml").unwrap();
+//!
+//! send_fds(&sender, &[("config", file.as_fd())]).unwrap();
+//!
+//! let received = recv_fds(&receiver).unwrap().unwrap();
+//! assert_eq!(received[0].0, "config");
+//! ```
+
+use crate::inherited::{InheritedFdError, InheritedFds, with_inherited_fds};
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    libc,
+    sys::socket::{ControlMessage, MsgFlags, sendmsg},
+};
+use std::{
+    io::{self, IoSlice, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    ptr,
+};
+use thiserror::Error;
+
+/// The maximum number of file descriptors which can be sent in a single batch. This is the Linux
+/// kernel's `SCM_MAX_FD`.
+pub const MAX_FDS_PER_BATCH: usize = 253;
+
+/// The maximum length of the names in a batch, to avoid allocating unreasonable amounts of memory
+/// if the peer misbehaves.
+const MAX_PAYLOAD_LENGTH: usize = 1 << 20;
+
+/// Errors that can occur while sending or receiving file descriptors.
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Error adding received file descriptors to the inherited registry
+    #[error(transparent)]
+    Inherited(#[from] InheritedFdError),
+
+    /// Too many file descriptors to send in one batch
+    #[error("Can't send {0} FDs in one batch, the maximum is {MAX_FDS_PER_BATCH}")]
+    TooManyFds(usize),
+
+    /// The names to send are too long
+    #[error("Names are too long to send")]
+    NamesTooLong,
+
+    /// Control message was truncated
+    #[error("Control message was truncated, so some FDs were lost")]
+    ControlTruncated,
+
+    /// The message received was not in the expected format
+    #[error("Malformed message received")]
+    Malformed,
+
+    /// The number of file descriptors received doesn't match the number of names
+    #[error("Expected {expected} FDs but received {received}")]
+    FdCountMismatch {
+        /// The number of names received.
+        expected: usize,
+        /// The number of file descriptors received.
+        received: usize,
+    },
+}
+
+/// Sends the given file descriptors and their names over the given socket as a single batch.
+pub fn send_fds(socket: &UnixStream, fds: &[(&str, BorrowedFd)]) -> Result<(), TransferError> {
+    let (payload, raw_fds) = encode(fds)?;
+    let sent = loop {
+        match send_with_fds(socket.as_raw_fd(), &payload, &raw_fds) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => break result?,
+        }
+    };
+    let mut socket = socket;
+    socket.write_all(&payload[sent..])?;
+    Ok(())
+}
+
+/// Receives a batch of file descriptors and their names sent by [`send_fds`] from the given
+/// socket.
+///
+/// The received file descriptors have the `FD_CLOEXEC` flag set. Returns `None` if the socket was
+/// closed by the peer before any more file descriptors were sent.
+pub fn recv_fds(socket: &UnixStream) -> Result<Option<Vec<(String, OwnedFd)>>, TransferError> {
+    let mut header = [0; 4];
+    let (received, fds) = loop {
+        match recv_with_fds(socket.as_raw_fd(), &mut header) {
+            Err(TransferError::Io(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => break result?,
+        }
+    };
+    if received == 0 {
+        return if fds.is_empty() {
+            Ok(None)
+        } else {
+            Err(TransferError::Malformed)
+        };
+    }
+    let mut socket = socket;
+    socket.read_exact(&mut header[received..])?;
+    let mut payload = vec![0; payload_length(header)?];
+    socket.read_exact(&mut payload)?;
+    decode(&payload, fds).map(Some)
+}
+
+/// Receives a batch of file descriptors and their names sent by [`send_fds`] from the given
+/// socket, and adds them to the given registry.
+///
+/// File descriptors with empty names are added without a name. Returns the file descriptors
+/// added, or `None` if the socket was closed by the peer.
+pub fn recv_fds_into(
+    socket: &UnixStream,
+    fds: &mut InheritedFds,
+) -> Result<Option<Vec<RawFd>>, TransferError> {
+    Ok(recv_fds(socket)?.map(|received| insert_received(fds, received)))
+}
+
+/// Receives a batch of file descriptors and their names sent by [`send_fds`] from the given
+/// socket, and adds them to the process-wide registry of inherited file descriptors, so they can
+/// be taken with [`take_named_fd_ownership`](crate::inherited::take_named_fd_ownership).
+///
+/// Returns the file descriptors added, or `None` if the socket was closed by the peer.
+pub fn recv_inherited_fds(socket: &UnixStream) -> Result<Option<Vec<RawFd>>, TransferError> {
+    let Some(received) = recv_fds(socket)? else {
+        return Ok(None);
+    };
+    Ok(Some(with_inherited_fds(|fds| {
+        insert_received(fds, received)
+    })?))
+}
+
+fn insert_received(fds: &mut InheritedFds, received: Vec<(String, OwnedFd)>) -> Vec<RawFd> {
+    received
+        .into_iter()
+        .map(|(name, fd)| {
+            let raw_fd = fd.as_raw_fd();
+            fds.insert(fd, Some(name).filter(|name| !name.is_empty()));
+            raw_fd
+        })
+        .collect()
+}
+
+/// Encodes the names of the given file descriptors into a message payload, and returns it along
+/// with the raw file descriptors to send with it.
+///
+/// The payload consists of a 32-bit length of the rest of the payload, followed by a 32-bit count
+/// of file descriptors and then a length-prefixed name for each. All integers are little-endian.
+fn encode(fds: &[(&str, BorrowedFd)]) -> Result<(Vec<u8>, Vec<RawFd>), TransferError> {
+    if fds.len() > MAX_FDS_PER_BATCH {
+        return Err(TransferError::TooManyFds(fds.len()));
+    }
+    let mut payload = vec![0; 4];
+    payload.extend_from_slice(&(fds.len() as u32).to_le_bytes());
+    for (name, _) in fds {
+        payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        payload.extend_from_slice(name.as_bytes());
+    }
+    if payload.len() - 4 > MAX_PAYLOAD_LENGTH {
+        return Err(TransferError::NamesTooLong);
+    }
+    let length = (payload.len() - 4) as u32;
+    payload[..4].copy_from_slice(&length.to_le_bytes());
+    Ok((payload, fds.iter().map(|(_, fd)| fd.as_raw_fd()).collect()))
+}
+
+/// Returns the length of the rest of the payload from its header.
+fn payload_length(header: [u8; 4]) -> Result<usize, TransferError> {
+    let length = u32::from_le_bytes(header) as usize;
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(TransferError::Malformed);
+    }
+    Ok(length)
+}
+
+/// Decodes the names from a message payload (after the length header), and matches them up with
+/// the received file descriptors.
+fn decode(payload: &[u8], fds: Vec<OwnedFd>) -> Result<Vec<(String, OwnedFd)>, TransferError> {
+    let mut remaining = payload;
+    let read_u32 = |remaining: &mut &[u8]| -> Result<usize, TransferError> {
+        let (bytes, rest) = remaining
+            .split_first_chunk::<4>()
+            .ok_or(TransferError::Malformed)?;
+        *remaining = rest;
+        Ok(u32::from_le_bytes(*bytes) as usize)
+    };
+
+    let count = read_u32(&mut remaining)?;
+    if count != fds.len() {
+        return Err(TransferError::FdCountMismatch {
+            expected: count,
+            received: fds.len(),
+        });
+    }
+    let mut names = Vec::with_capacity(count);
+    for _ in 0..count {
+        let length = read_u32(&mut remaining)?;
+        if length > remaining.len() {
+            return Err(TransferError::Malformed);
+        }
+        let (name, rest) = remaining.split_at(length);
+        remaining = rest;
+        names.push(String::from_utf8(name.to_owned()).map_err(|_| TransferError::Malformed)?);
+    }
+    if !remaining.is_empty() {
+        return Err(TransferError::Malformed);
+    }
+    Ok(names.into_iter().zip(fds).collect())
+}
+
+/// Sends as much of the given payload as possible with a single `sendmsg` call, attaching the
+/// given file descriptors. Returns the number of bytes sent.
+fn send_with_fds(socket: RawFd, payload: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let control = [ControlMessage::ScmRights(fds)];
+    let control: &[ControlMessage] = if fds.is_empty() { &[] } else { &control };
+    Ok(sendmsg::<()>(
+        socket,
+        &[IoSlice::new(payload)],
+        control,
+        MsgFlags::MSG_NOSIGNAL,
+        None,
+    )?)
+}
+
+/// Receives into the given buffer with a single `recvmsg` call, along with any file descriptors
+/// sent with the data. Returns the number of bytes received and the file descriptors.
+///
+/// If the control message was truncated then any file descriptors which were received are
+/// closed, and an error is returned.
+fn recv_with_fds(socket: RawFd, buffer: &mut [u8]) -> Result<(usize, Vec<OwnedFd>), TransferError> {
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_FDS_PER_BATCH]);
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    // SAFETY: All fields of `msghdr` are integers or pointers, for which zero is a valid value.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buffer.as_mut_ptr().cast();
+    msg.msg_controllen = cmsg_buffer.len() as _;
+
+    // SAFETY: `msg` points to valid buffers of the given lengths, which outlive the call.
+    let received =
+        Errno::result(unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) })
+            .map_err(io::Error::from)?;
+
+    // Take ownership of all file descriptors received, even if the control message was
+    // truncated, so that they are closed rather than leaked.
+    let mut fds = Vec::new();
+    // SAFETY: `msg` was filled in by `recvmsg` above, so the control messages it points to are
+    // valid.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count =
+                    ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                for i in 0..count {
+                    // The kernel just installed these FDs for us, so nothing else owns them.
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(TransferError::ControlTruncated);
+    }
+    Ok((received as usize, fds))
+}
+
+/// Asynchronous versions of the functions to send and receive file descriptors, for use with
+/// tokio.
+#[cfg(feature = "tokio")]
+pub mod tokio {
+    use super::{
+        InheritedFds, TransferError, decode, encode, insert_received, payload_length,
+        recv_with_fds, send_with_fds,
+    };
+    use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, Interest},
+        net::UnixStream,
+    };
+
+    /// Sends the given file descriptors and their names over the given socket as a single batch.
+    ///
+    /// See [`send_fds`](super::send_fds).
+    pub async fn send_fds(
+        socket: &mut UnixStream,
+        fds: &[(&str, BorrowedFd<'_>)],
+    ) -> Result<(), TransferError> {
+        let (payload, raw_fds) = encode(fds)?;
+        let sent = socket
+            .async_io(Interest::WRITABLE, || {
+                send_with_fds(socket.as_raw_fd(), &payload, &raw_fds)
+            })
+            .await?;
+        socket.write_all(&payload[sent..]).await?;
+        Ok(())
+    }
+
+    /// Receives a batch of file descriptors and their names sent by [`send_fds`] from the given
+    /// socket.
+    ///
+    /// See [`recv_fds`](super::recv_fds).
+    pub async fn recv_fds(
+        socket: &mut UnixStream,
+    ) -> Result<Option<Vec<(String, OwnedFd)>>, TransferError> {
+        let mut header = [0; 4];
+        let (received, fds) = loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || {
+                recv_with_fds(socket.as_raw_fd(), &mut header).map_err(|e| match e {
+                    TransferError::Io(e) => e,
+                    e => std::io::Error::other(e),
+                })
+            }) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(unwrap_transfer_error(e)),
+                Ok(result) => break result,
+            }
+        };
+        if received == 0 {
+            return if fds.is_empty() {
+                Ok(None)
+            } else {
+                Err(TransferError::Malformed)
+            };
+        }
+        socket.read_exact(&mut header[received..]).await?;
+        let mut payload = vec![0; payload_length(header)?];
+        socket.read_exact(&mut payload).await?;
+        decode(&payload, fds).map(Some)
+    }
+
+    /// Receives a batch of file descriptors and their names sent by [`send_fds`] from the given
+    /// socket, and adds them to the given registry.
+    ///
+    /// See [`recv_fds_into`](super::recv_fds_into).
+    pub async fn recv_fds_into(
+        socket: &mut UnixStream,
+        fds: &mut InheritedFds,
+    ) -> Result<Option<Vec<RawFd>>, TransferError> {
+        Ok(recv_fds(socket)
+            .await?
+            .map(|received| insert_received(fds, received)))
+    }
+
+    /// Converts an I/O error which may wrap a `TransferError` back to the `TransferError`.
+    ///
+    /// `try_io` requires an I/O error, but we want to preserve the original error, while letting
+    /// `WouldBlock` errors through directly so that tokio knows to wait for readiness again.
+    fn unwrap_transfer_error(e: std::io::Error) -> TransferError {
+        e.downcast::<TransferError>()
+            .unwrap_or_else(TransferError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup;
+    use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+    use std::{fs::File, io::Seek, os::fd::AsFd};
+    use tempfile::tempfile;
+
+    fn file_with_contents(contents: &[u8]) -> File {
+        let mut file = tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        file.rewind().unwrap();
+        file
+    }
+
+    fn read_contents(fd: OwnedFd) -> String {
+        let mut contents = String::new();
+        File::from(fd).read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn round_trip() {
+        setup();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file1 = file_with_contents(b"one");
+        let file2 = file_with_contents(b"two");
+
+        send_fds(&sender, &[("a", file1.as_fd()), ("b", file2.as_fd())]).unwrap();
+        send_fds(&sender, &[]).unwrap();
+        drop(sender);
+
+        let received = recv_fds(&receiver).unwrap().unwrap();
+        assert_eq!(received.len(), 2);
+        let flags = fcntl(&received[0].1, FcntlArg::F_GETFD).unwrap();
+        assert_eq!(flags, FdFlag::FD_CLOEXEC.bits());
+        let contents: Vec<(String, String)> = received
+            .into_iter()
+            .map(|(name, fd)| (name, read_contents(fd)))
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                ("a".to_owned(), "one".to_owned()),
+                ("b".to_owned(), "two".to_owned())
+            ]
+        );
+
+        assert!(recv_fds(&receiver).unwrap().unwrap().is_empty());
+        assert!(recv_fds(&receiver).unwrap().is_none());
+    }
+
+    #[test]
+    fn into_registry() {
+        setup();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = file_with_contents(b"one");
+        let mut fds = InheritedFds::new();
+
+        send_fds(&sender, &[("file", file.as_fd()), ("", file.as_fd())]).unwrap();
+        let raw_fds = recv_fds_into(&receiver, &mut fds).unwrap().unwrap();
+
+        assert_eq!(raw_fds.len(), 2);
+        assert_eq!(fds.names().collect::<Vec<_>>(), vec![("file", raw_fds[0])]);
+        assert_eq!(read_contents(fds.take_by_name("file").unwrap()), "one");
+        assert!(fds.take(raw_fds[1]).is_ok());
+    }
+
+    #[test]
+    fn too_many_fds() {
+        setup();
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let file = tempfile().unwrap();
+        let fds = vec![("", file.as_fd()); MAX_FDS_PER_BATCH + 1];
+
+        assert!(matches!(
+            send_fds(&sender, &fds),
+            Err(TransferError::TooManyFds(254))
+        ));
+    }
+
+    #[test]
+    fn count_mismatch() {
+        setup();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = tempfile().unwrap();
+
+        // Claim to send two FDs but only actually send one.
+        let (payload, _) = encode(&[("a", file.as_fd()), ("b", file.as_fd())]).unwrap();
+        send_with_fds(sender.as_raw_fd(), &payload, &[file.as_raw_fd()]).unwrap();
+
+        assert!(matches!(
+            recv_fds(&receiver),
+            Err(TransferError::FdCountMismatch {
+                expected: 2,
+                received: 1
+            })
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[::tokio::test(crate = "::tokio")]
+    async fn round_trip_async() {
+        setup();
+        let (mut sender, mut receiver) = ::tokio::net::UnixStream::pair().unwrap();
+        let file = file_with_contents(b"async");
+
+        let mut fds = InheritedFds::new();
+        // Start receiving before anything is sent, so that the receiver has to wait.
+        let (received, ()) = ::tokio::join!(
+            super::tokio::recv_fds_into(&mut receiver, &mut fds),
+            async {
+                super::tokio::send_fds(&mut sender, &[("a", file.as_fd())])
+                    .await
+                    .unwrap();
+                drop(sender);
+            }
+        );
+
+        let raw_fds = received.unwrap().unwrap();
+        assert_eq!(fds.name(raw_fds[0]), Some("a"));
+        assert_eq!(read_contents(fds.take(raw_fds[0]).unwrap()), "async");
+        assert!(
+            super::tokio::recv_fds(&mut receiver)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
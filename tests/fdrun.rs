@@ -0,0 +1,90 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end tests running commands through the `fdrun` binary.
+
+use command_fds::{CommandFdExt, FdMapping};
+use std::{
+    fs::{File, read_to_string},
+    process::Command,
+};
+use tempfile::TempDir;
+
+fn fdrun() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_fdrun"))
+}
+
+#[test]
+fn files_and_sockets() {
+    let dir = TempDir::new().unwrap();
+    let output_path = dir.path().join("output");
+    let output = fdrun()
+        .arg("--fd")
+        .arg("3=file:testdata/file1.txt")
+        .arg("--fd")
+        .arg(format!("4=file:{}:wo", output_path.display()))
+        .arg("--fd")
+        .arg("5=tcp-listen:127.0.0.1:0")
+        .arg("--fd=6=inherit:1")
+        .args([
+            "sh",
+            "-c",
+            "cat <&3 >&4 && [ -S /proc/self/fd/5 ] && echo ok >&6",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"ok\n");
+    assert_eq!(
+        read_to_string(output_path).unwrap(),
+        read_to_string("testdata/file1.txt").unwrap()
+    );
+}
+
+#[test]
+fn only_mapped() {
+    let script = "[ -e /proc/self/fd/7 ] && [ -e /proc/self/fd/8 ]";
+    let run = |only_mapped: bool| {
+        let mut command = fdrun();
+        if only_mapped {
+            command.arg("--only-mapped");
+        }
+        command
+            .args(["--fd", "8=inherit:7", "sh", "-c", script])
+            .fd_mappings(vec![FdMapping {
+                parent_fd: File::open("testdata/file2.txt").unwrap().into(),
+                child_fd: 7,
+            }])
+            .unwrap();
+        command.status().unwrap()
+    };
+    assert!(run(false).success());
+    assert_eq!(run(true).code(), Some(1));
+}
+
+#[test]
+fn errors() {
+    let output = fdrun()
+        .args(["--fd", "3=bogus:x", "true"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = fdrun()
+        .args(["--fd", "3=file:/nonexistent/file", "true"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(127));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/nonexistent/file"));
+}
@@ -0,0 +1,97 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end tests for the `socket-activate` binary.
+
+use std::{
+    io::Read,
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Command, Stdio},
+    thread::sleep,
+    time::Duration,
+};
+use tempfile::TempDir;
+
+const CHECK_PROTOCOL: &str = r#"[ "$LISTEN_PID" = $$ ] && [ -S /proc/self/fd/3 ] && echo "$LISTEN_FDS $LISTEN_FDNAMES $FOO""#;
+
+fn socket_activate() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_socket-activate"))
+}
+
+/// Connects to the Unix socket at the given path, retrying until it exists.
+fn connect(path: &Path) -> UnixStream {
+    for _ in 0..500 {
+        if let Ok(stream) = UnixStream::connect(path) {
+            return stream;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    panic!("Failed to connect to {path:?}");
+}
+
+#[test]
+fn now() {
+    let dir = TempDir::new().unwrap();
+    let output = socket_activate()
+        .arg("--now")
+        .arg("-l")
+        .arg(format!(
+            "app=unix:{}",
+            dir.path().join("app.sock").display()
+        ))
+        .args(["-E", "FOO=bar", "sh", "-c", CHECK_PROTOCOL])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"1 app bar\n");
+}
+
+#[test]
+fn wait_for_connection() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("app.sock");
+    let child = socket_activate()
+        .arg("-l")
+        .arg(&path)
+        .args(["sh", "-c", CHECK_PROTOCOL])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let _stream = connect(&path);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"1 unknown \n");
+}
+
+#[test]
+fn accept() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("app.sock");
+    let mut child = socket_activate()
+        .arg("--accept")
+        .arg("-l")
+        .arg(&path)
+        .args(["sh", "-c", &format!("{{ {CHECK_PROTOCOL}; }} >&3")])
+        .spawn()
+        .unwrap();
+    for _ in 0..2 {
+        let mut stream = connect(&path);
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "1 connection \n");
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+}